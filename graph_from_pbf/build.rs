@@ -0,0 +1,35 @@
+use std::process::Command;
+
+// Exposes the git commit the binary was built from, and whether the working tree had uncommitted
+// changes, for the manifest. Both are "unknown" when built outside a git checkout.
+fn main() {
+    let commit = git(&["rev-parse", "HEAD"]).unwrap_or("unknown".to_string());
+    let dirty = match git(&["status", "--porcelain"]) {
+        Some(status) => (!status.is_empty()).to_string(),
+        None => "unknown".to_string(),
+    };
+    println!("cargo:rustc-env=GIT_COMMIT={commit}");
+    println!("cargo:rustc-env=GIT_DIRTY={dirty}");
+
+    // rerun when HEAD moves or the index changes, rather than only when this file does
+    if let Some(git_dir) = git(&["rev-parse", "--git-dir"]) {
+        println!("cargo:rerun-if-changed={git_dir}/HEAD");
+        println!("cargo:rerun-if-changed={git_dir}/index");
+        if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed={git_dir}/{head_ref}");
+        }
+    }
+    // and when the sources or settings change, so uncommitted edits show in GIT_DIRTY
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=settings");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
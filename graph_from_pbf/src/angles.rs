@@ -1,6 +1,6 @@
-use graph_from_pbf::Edge;
+use graph_from_pbf::{Edge, Settings};
 
-use geo::{LineString, Point, RhumbBearing};
+use geo::{Coord, GeodesicBearing, HaversineLength, LineString, Point, RhumbBearing};
 use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
 use rayon::prelude::*;
 use serde::Serialize;
@@ -14,7 +14,7 @@ pub struct Angles {
     pub backward_departure: u16,
 }
 
-pub fn calculate(edges: &Vec<Edge>) -> HashMap<usize, Angles> {
+pub fn calculate(edges: &Vec<Edge>, settings: &Settings) -> HashMap<usize, Angles> {
     println!("Calculating angle from north of arrival and departure");
     let progress = ProgressBar::new(edges.len() as u64).with_style(ProgressStyle::with_template(
         "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {human_pos}/{human_len} ({per_sec}, {eta})").unwrap());
@@ -23,28 +23,68 @@ pub fn calculate(edges: &Vec<Edge>) -> HashMap<usize, Angles> {
         .into_par_iter()
         .progress_with(progress)
         .map(|edge| {
-            let angles: Angles = arrival_and_departure_angles_from_north(&edge.linestring);
+            let angles: Angles = arrival_and_departure_angles_from_north(
+                &edge.linestring,
+                settings.bearing_distance.unwrap_or(0.0),
+                settings.geodesic_bearings,
+            );
             (edge.id, angles)
         })
         .collect();
     angles
 }
 
-fn arrival_and_departure_angles_from_north(linestring: &LineString) -> Angles {
+// Bearings at each end of the edge are taken towards the point bearing_distance metres along it,
+// so small kinks at junctions don't dominate. A distance of 0 uses the neighbouring vertex.
+fn arrival_and_departure_angles_from_north(
+    linestring: &LineString,
+    bearing_distance: f32,
+    geodesic: bool,
+) -> Angles {
     let first_point = linestring.points().next().unwrap();
-    let second_point = linestring.points().nth(1).unwrap();
     let last_point = linestring.points().last().unwrap();
-    let second_last_point = linestring.points().nth_back(1).unwrap();
+    let mut reversed = linestring.clone();
+    reversed.0.reverse();
+    let point_from_start = point_along(linestring, bearing_distance);
+    let point_from_end = point_along(&reversed, bearing_distance);
 
     Angles {
-        forward_arrival: get_angle(&first_point, &second_point),
-        forward_departure: get_angle(&second_last_point, &last_point),
-        backward_arrival: get_angle(&last_point, &second_last_point),
-        backward_departure: get_angle(&second_point, &first_point),
+        forward_arrival: get_angle(&first_point, &point_from_start, geodesic),
+        forward_departure: get_angle(&point_from_end, &last_point, geodesic),
+        backward_arrival: get_angle(&last_point, &point_from_end, geodesic),
+        backward_departure: get_angle(&point_from_start, &first_point, geodesic),
     }
 }
 
-fn get_angle(a: &Point, b: &Point) -> u16 {
-    let angle_from_north = a.rhumb_bearing(*b).round() as u16;
+// The point the given distance along the linestring, clamped to its length, and at least as far
+// as the second vertex
+fn point_along(linestring: &LineString, distance: f32) -> Point {
+    let mut travelled: f32 = 0.0;
+    for (idx, line) in linestring.lines().enumerate() {
+        let length = line.haversine_length() as f32;
+        if idx == 0 || travelled + length < distance {
+            travelled += length;
+            if travelled >= distance {
+                return Point::from(line.end);
+            }
+            continue;
+        }
+        let fraction = ((distance - travelled) / length) as f64;
+        return Point::from(Coord {
+            x: line.start.x + (line.end.x - line.start.x) * fraction,
+            y: line.start.y + (line.end.y - line.start.y) * fraction,
+        });
+    }
+    linestring.points().last().unwrap()
+}
+
+fn get_angle(a: &Point, b: &Point, geodesic: bool) -> u16 {
+    let bearing = if geodesic {
+        a.geodesic_bearing(*b)
+    } else {
+        a.rhumb_bearing(*b)
+    };
+    // geodesic bearings range from -180 to 180
+    let angle_from_north = bearing.round().rem_euclid(360.0) as u16;
     angle_from_north
 }
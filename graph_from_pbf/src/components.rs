@@ -0,0 +1,191 @@
+use crate::report::Report;
+use crate::*;
+use std::collections::HashMap;
+use std::io::BufWriter;
+
+use fs_err::File;
+use geo::Coord;
+use geojson::{Feature, FeatureWriter, Geometry};
+
+// Small fragments disconnected from the rest of the network (a private driveway, a path mapped
+// alone in a field) would otherwise attract origins snapping to the nearest node. Returns the
+// kept edges, and the removed edges with the id and size of their component.
+pub fn remove_islands(
+    edges: Vec<Edge>,
+    graph_nodes_lookup: &HashMap<i64, (usize, Coord)>,
+    policy: &IslandPolicy,
+    report: &mut Report,
+) -> (Vec<Edge>, Vec<(Edge, usize, usize)>) {
+    println!("Finding strongly connected components");
+    let mut adjacency: Vec<Vec<usize>> = vec![Vec::new(); graph_nodes_lookup.len()];
+    for edge in &edges {
+        let start = graph_nodes_lookup[&edge.start_node].0;
+        let end = graph_nodes_lookup[&edge.end_node].0;
+        if edge.forward {
+            adjacency[start].push(end);
+        }
+        if edge.backward {
+            adjacency[end].push(start);
+        }
+    }
+    let component = strongly_connected_components(&adjacency);
+    let component_count = component.iter().max().map_or(0, |max| max + 1);
+    let mut component_sizes = vec![0; component_count];
+    for c in &component {
+        component_sizes[*c] += 1;
+    }
+    let largest_component_size = component_sizes.iter().copied().max().unwrap_or(0);
+
+    let keep: Vec<bool> = match policy {
+        IslandPolicy::KeepAll => vec![true; component_count],
+        IslandPolicy::Largest => {
+            // ties are broken by keeping the first largest component only
+            let largest = component_sizes
+                .iter()
+                .position(|size| *size == largest_component_size);
+            (0..component_count).map(|c| Some(c) == largest).collect()
+        }
+        IslandPolicy::MinSize(min_size) => component_sizes
+            .iter()
+            .map(|size| size >= min_size)
+            .collect(),
+    };
+
+    // Edges between two kept components (e.g. a one-way link) are kept too
+    let mut kept_edges: Vec<Edge> = Vec::new();
+    let mut removed_edges: Vec<(Edge, usize, usize)> = Vec::new();
+    for edge in edges {
+        let start_component = component[graph_nodes_lookup[&edge.start_node].0];
+        let end_component = component[graph_nodes_lookup[&edge.end_node].0];
+        if keep[start_component] && keep[end_component] {
+            kept_edges.push(edge);
+        } else {
+            let removed_component = if keep[start_component] {
+                end_component
+            } else {
+                start_component
+            };
+            removed_edges.push((edge, removed_component, component_sizes[removed_component]));
+        }
+    }
+
+    report.islands.component_count = component_count;
+    report.islands.largest_component_size = largest_component_size;
+    report.islands.kept_component_count = keep.iter().filter(|k| **k).count();
+    report.islands.removed_node_count = component.iter().filter(|c| !keep[**c]).count();
+    report.islands.removed_edge_count = removed_edges.len();
+    println!(
+        "Found {} components, removed {} nodes and {} edges",
+        component_count, report.islands.removed_node_count, report.islands.removed_edge_count
+    );
+    (kept_edges, removed_edges)
+}
+
+pub fn write_removed(
+    removed_edges: &Vec<(Edge, usize, usize)>,
+    output_directory: &str,
+    mode: &str,
+) -> Result<()> {
+    let path = format!("{output_directory}/{mode}_removed_islands.geojson");
+    let mut out = FeatureWriter::from_writer(BufWriter::new(File::create(path)?));
+    for (edge, component, component_size) in removed_edges {
+        let mut f = Feature::from(Geometry::from(&edge.linestring));
+        f.set_property("osm_way_id", edge.osm_id);
+        f.set_property("component", *component);
+        f.set_property("component_size", *component_size);
+        out.write_feature(&f)?;
+    }
+    Ok(())
+}
+
+// Iterative version of Tarjan's algorithm, returning the component id of each node
+fn strongly_connected_components(adjacency: &Vec<Vec<usize>>) -> Vec<usize> {
+    let num_nodes = adjacency.len();
+    let mut index = vec![usize::MAX; num_nodes];
+    let mut lowlink = vec![0; num_nodes];
+    let mut on_stack = vec![false; num_nodes];
+    let mut stack: Vec<usize> = Vec::new();
+    let mut component = vec![usize::MAX; num_nodes];
+    let mut next_index: usize = 0;
+    let mut component_count: usize = 0;
+
+    for root in 0..num_nodes {
+        if index[root] != usize::MAX {
+            continue;
+        }
+        // (node, position of the next neighbour to visit)
+        let mut call_stack: Vec<(usize, usize)> = vec![(root, 0)];
+        index[root] = next_index;
+        lowlink[root] = next_index;
+        next_index += 1;
+        stack.push(root);
+        on_stack[root] = true;
+
+        while let Some((node, next_neighbour)) = call_stack.last().copied() {
+            if next_neighbour < adjacency[node].len() {
+                call_stack.last_mut().unwrap().1 += 1;
+                let neighbour = adjacency[node][next_neighbour];
+                if index[neighbour] == usize::MAX {
+                    index[neighbour] = next_index;
+                    lowlink[neighbour] = next_index;
+                    next_index += 1;
+                    stack.push(neighbour);
+                    on_stack[neighbour] = true;
+                    call_stack.push((neighbour, 0));
+                } else if on_stack[neighbour] {
+                    lowlink[node] = lowlink[node].min(index[neighbour]);
+                }
+                continue;
+            }
+
+            call_stack.pop();
+            if let Some((parent, _)) = call_stack.last() {
+                lowlink[*parent] = lowlink[*parent].min(lowlink[node]);
+            }
+            if lowlink[node] == index[node] {
+                loop {
+                    let member = stack.pop().unwrap();
+                    on_stack[member] = false;
+                    component[member] = component_count;
+                    if member == node {
+                        break;
+                    }
+                }
+                component_count += 1;
+            }
+        }
+    }
+    component
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The nodes of each component, ordered by their lowest node
+    fn components(adjacency: &Vec<Vec<usize>>) -> Vec<Vec<usize>> {
+        let component = strongly_connected_components(adjacency);
+        let mut members: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (node, c) in component.into_iter().enumerate() {
+            members.entry(c).or_insert_with(Vec::new).push(node);
+        }
+        let mut components: Vec<Vec<usize>> = members.into_values().collect();
+        components.sort();
+        components
+    }
+
+    #[test]
+    fn two_components() {
+        // a triangle and a separate two-way link
+        let adjacency = vec![vec![1], vec![2], vec![0], vec![4], vec![3]];
+        assert_eq!(components(&adjacency), vec![vec![0, 1, 2], vec![3, 4]]);
+    }
+
+    #[test]
+    fn one_way_links_into_a_sink() {
+        // 0 and 1 are linked both ways, with one-way links 1 -> 2 and 0 -> 3 into the two-way
+        // pair 2 and 3, which can't get back. 4 is only reached from 3.
+        let adjacency = vec![vec![1, 3], vec![0, 2], vec![3], vec![2, 4], vec![]];
+        assert_eq!(components(&adjacency), vec![vec![0, 1], vec![2, 3], vec![4]]);
+    }
+}
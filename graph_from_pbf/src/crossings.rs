@@ -0,0 +1,175 @@
+use crate::*;
+use std::collections::HashMap;
+use std::io::BufWriter;
+
+use fs_err::File;
+use geo::line_intersection::{line_intersection, LineIntersection};
+use geo::{Coord, Line, Point};
+use geojson::{Feature, FeatureWriter, Geometry};
+use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+
+// Size of the grid cells used to find candidate pairs of segments, in degrees
+const CELL_SIZE: f64 = 0.001;
+
+// Ways crossing on the same level should share a node at the junction. Crossings between
+// different layers, or between a bridge/tunnel and a way following the terrain, are expected and
+// not reported. Besides ways crossing, the end of a way touching the middle of another without a
+// shared node is reported, the usual form of a missing T-junction.
+pub fn write_unconnected(edges: &Vec<Edge>, output_directory: &str, mode: &str) -> Result<()> {
+    println!("Finding ways crossing without a shared node");
+    let crossings = find_unconnected(edges);
+    println!("Found {} unconnected crossings", crossings.len());
+
+    let path = format!("{output_directory}/{mode}_unconnected_crossings.geojson");
+    let mut out = FeatureWriter::from_writer(BufWriter::new(File::create(path)?));
+    for (intersection, edge_idx1, edge_idx2, touching) in crossings {
+        let (edge1, edge2) = (&edges[edge_idx1], &edges[edge_idx2]);
+        let mut f = Feature::from(Geometry::from(&Point(intersection)));
+        f.set_property("osm_way_ids", vec![edge1.osm_id, edge2.osm_id]);
+        f.set_property("layer", edge1.layer);
+        f.set_property("follows_terrain", edge1.follows_terrain);
+        f.set_property("kind", if touching { "touching" } else { "crossing" });
+        out.write_feature(&f)?;
+    }
+    Ok(())
+}
+
+// The points where two edges cross or touch without a shared node, with the indices of the edges
+// and whether they only touch
+fn find_unconnected(edges: &Vec<Edge>) -> Vec<(Coord, usize, usize, bool)> {
+    // each segment with its edge index and the OSM node ids at its ends
+    let mut grid: HashMap<(i32, i32), Vec<(usize, Line, [i64; 2])>> = HashMap::new();
+    for (edge_idx, edge) in edges.iter().enumerate() {
+        for (line, nodes) in edge.linestring.lines().zip(edge.node_ids.windows(2)) {
+            let (min_x, min_y) = cell(&Coord {
+                x: line.start.x.min(line.end.x),
+                y: line.start.y.min(line.end.y),
+            });
+            let (max_x, max_y) = cell(&Coord {
+                x: line.start.x.max(line.end.x),
+                y: line.start.y.max(line.end.y),
+            });
+            for x in min_x..=max_x {
+                for y in min_y..=max_y {
+                    grid.entry((x, y))
+                        .or_insert_with(Vec::new)
+                        .push((edge_idx, line, [nodes[0], nodes[1]]));
+                }
+            }
+        }
+    }
+
+    let progress = ProgressBar::new(grid.len() as u64).with_style(ProgressStyle::with_template(
+        "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {human_pos}/{human_len} ({per_sec}, {eta})").unwrap());
+    let mut crossings: Vec<(Coord, usize, usize, bool)> = grid
+        .par_iter()
+        .progress_with(progress)
+        .flat_map_iter(|(cell_key, segments)| {
+            let mut crossings = Vec::new();
+            for (i, (edge_idx1, line1, nodes1)) in segments.iter().enumerate() {
+                for (edge_idx2, line2, nodes2) in &segments[i + 1..] {
+                    let (edge1, edge2) = (&edges[*edge_idx1], &edges[*edge_idx2]);
+                    if edge_idx1 == edge_idx2
+                        || edge1.layer != edge2.layer
+                        || edge1.follows_terrain != edge2.follows_terrain
+                    {
+                        continue;
+                    }
+                    let Some(LineIntersection::SinglePoint {
+                        intersection,
+                        is_proper,
+                    }) = line_intersection(*line1, *line2)
+                    else {
+                        continue;
+                    };
+                    // Segments meeting at a shared node touch at that node, which is fine
+                    if !is_proper {
+                        let node1 = end_node_at(line1, nodes1, intersection);
+                        let node2 = end_node_at(line2, nodes2, intersection);
+                        if node1.is_some() && node1 == node2 {
+                            continue;
+                        }
+                    }
+                    // Segments spanning several cells are checked in each of them, so only
+                    // keep the crossing in the cell containing it
+                    if cell(&intersection) == *cell_key {
+                        crossings.push((intersection, *edge_idx1, *edge_idx2, !is_proper));
+                    }
+                }
+            }
+            crossings
+        })
+        .collect();
+    // A way touching another at a vertex is found once for each of its segments meeting there
+    let key = |(intersection, edge_idx1, edge_idx2, _): &(Coord, usize, usize, bool)| {
+        let (osm_id1, osm_id2) = (edges[*edge_idx1].osm_id, edges[*edge_idx2].osm_id);
+        (intersection.x, intersection.y, osm_id1.min(osm_id2), osm_id1.max(osm_id2))
+    };
+    crossings.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+    crossings.dedup_by(|a, b| key(a) == key(b));
+    crossings
+}
+
+// The OSM node at the end of the segment lying at the point, if any
+fn end_node_at(line: &Line, nodes: &[i64; 2], point: Coord) -> Option<i64> {
+    if line.start == point {
+        Some(nodes[0])
+    } else if line.end == point {
+        Some(nodes[1])
+    } else {
+        None
+    }
+}
+
+fn cell(coord: &Coord) -> (i32, i32) {
+    (
+        (coord.x / CELL_SIZE).floor() as i32,
+        (coord.y / CELL_SIZE).floor() as i32,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::edge;
+
+    #[test]
+    fn crossing_and_touching_ways() {
+        let edges = vec![
+            edge(0, 100, vec![1, 2], vec![(0.0, 0.0), (0.0004, 0.0)]),
+            // crosses way 100 between its nodes
+            edge(1, 101, vec![3, 4], vec![(0.0002, -0.0001), (0.0002, 0.0001)]),
+            // ends on the middle of way 100
+            edge(2, 102, vec![5, 6], vec![(0.0003, 0.0), (0.0003, 0.0002)]),
+            // meets way 100 at its node 2
+            edge(3, 103, vec![2, 7], vec![(0.0004, 0.0), (0.0005, 0.0001)]),
+        ];
+        let mut found: Vec<(i64, i64, bool)> = find_unconnected(&edges)
+            .into_iter()
+            .map(|(_, edge_idx1, edge_idx2, touching)| {
+                (edges[edge_idx1].osm_id, edges[edge_idx2].osm_id, touching)
+            })
+            .collect();
+        found.sort();
+        assert_eq!(found, vec![(100, 101, false), (100, 102, true)]);
+    }
+
+    #[test]
+    fn touching_at_a_vertex_is_reported_once() {
+        let edges = vec![
+            edge(0, 100, vec![1, 2], vec![(0.0, 0.0), (0.0004, 0.0)]),
+            // passes through a point on way 100 at its node 4, without way 100 having a node there
+            edge(1, 101, vec![3, 4, 5], vec![(0.0002, -0.0001), (0.0002, 0.0), (0.0002, 0.0001)]),
+            // on a bridge, so not expected to connect
+            Edge {
+                follows_terrain: false,
+                ..edge(2, 102, vec![6, 7], vec![(0.0001, -0.0001), (0.0001, 0.0001)])
+            },
+        ];
+        let found = find_unconnected(&edges);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, Coord { x: 0.0002, y: 0.0 });
+        assert!(found[0].3);
+    }
+}
@@ -0,0 +1,345 @@
+use crate::*;
+use graph_from_pbf::Interpolation;
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use elevation::GeoTiffElevation;
+use fs_err::File;
+use serde::Deserialize;
+use tiff::decoder::Decoder;
+use tiff::tags::Tag;
+
+// A digital elevation model made of one or more rasters: a single GeoTIFF or SRTM .hgt tile, a
+// directory of them, or a JSON mosaic definition listing paths with priorities. Each point takes
+// its height from the highest priority raster covering it, preferring finer resolutions on ties.
+pub struct Dem {
+    sources: Vec<Source>,
+    interpolation: Interpolation,
+}
+
+// An entry of a mosaic definition, e.g. {"path": "../input/lidar_2m", "priority": 1}
+#[derive(Deserialize)]
+struct MosaicEntry {
+    path: String,
+    #[serde(default)]
+    priority: i32,
+}
+
+struct Source {
+    path: String,
+    priority: i32,
+    format: Format,
+    grid: Grid,
+    nodata: Option<f32>, // the GDAL NoData value of a GeoTIFF, marking sea and voids
+    // opened on first use, so tiles away from the edges being processed are never read
+    reader: Option<Reader>,
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    GeoTiff,
+    Hgt,
+}
+
+enum Reader {
+    GeoTiff(GeoTiffElevation<BufReader<File>>),
+    Hgt(Arc<Vec<i16>>),
+}
+
+// Decoded .hgt tiles by path, shared between the DEMs opened on each thread
+static HGT_TILES: OnceLock<Mutex<HashMap<String, Arc<Vec<i16>>>>> = OnceLock::new();
+
+// Position and size of the raster cells, in degrees
+#[derive(Clone, Copy)]
+struct Grid {
+    first_x: f64, // centre of the north west cell
+    first_y: f64,
+    cell_width: f64,
+    cell_height: f64,
+    cols: usize,
+    rows: usize,
+}
+
+impl Dem {
+    pub fn open(path: &str, interpolation: Interpolation) -> Result<Dem> {
+        let mut sources: Vec<Source> = Vec::new();
+        if path.ends_with(".json") {
+            let mosaic: Vec<MosaicEntry> = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+            for entry in mosaic {
+                add_sources(&entry.path, entry.priority, &mut sources)?;
+            }
+        } else {
+            add_sources(path, 0, &mut sources)?;
+        }
+        if sources.is_empty() {
+            anyhow::bail!("No GeoTIFF or .hgt rasters found in {path}");
+        }
+        sources.sort_by(|a, b| {
+            b.priority
+                .cmp(&a.priority)
+                .then(a.grid.cell_width.total_cmp(&b.grid.cell_width))
+        });
+        Ok(Dem {
+            sources,
+            interpolation,
+        })
+    }
+
+    pub fn source_paths(&self) -> Vec<&str> {
+        self.sources.iter().map(|source| source.path.as_str()).collect()
+    }
+
+    pub fn height(&mut self, lon: f64, lat: f64) -> Option<f32> {
+        let interpolation = self.interpolation;
+        self.sources
+            .iter_mut()
+            .filter(|source| source.grid.contains(lon, lat))
+            .find_map(|source| source.height(lon, lat, interpolation))
+    }
+}
+
+fn add_sources(path: &str, priority: i32, sources: &mut Vec<Source>) -> Result<()> {
+    if Path::new(path).is_dir() {
+        let mut paths: Vec<String> = fs_err::read_dir(path)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path().to_string_lossy().to_string())
+            .collect();
+        paths.sort();
+        for path in paths {
+            if format(&path).is_some() {
+                sources.push(Source::new(path, priority)?);
+            }
+        }
+    } else {
+        sources.push(Source::new(path.to_string(), priority)?);
+    }
+    Ok(())
+}
+
+fn format(path: &str) -> Option<Format> {
+    let lowercase = path.to_lowercase();
+    if lowercase.ends_with(".tif") || lowercase.ends_with(".tiff") {
+        Some(Format::GeoTiff)
+    } else if lowercase.ends_with(".hgt") {
+        Some(Format::Hgt)
+    } else {
+        None
+    }
+}
+
+impl Source {
+    fn new(path: String, priority: i32) -> Result<Source> {
+        let format = format(&path).unwrap_or(Format::GeoTiff);
+        let (grid, nodata) = match format {
+            Format::GeoTiff => read_geotiff_grid(&path)?,
+            Format::Hgt => (read_hgt_grid(&path)?, None),
+        };
+        Ok(Source {
+            path,
+            priority,
+            format,
+            grid,
+            nodata,
+            reader: None,
+        })
+    }
+
+    fn height(&mut self, lon: f64, lat: f64, interpolation: Interpolation) -> Option<f32> {
+        let col = (lon - self.grid.first_x) / self.grid.cell_width;
+        let row = (self.grid.first_y - lat) / self.grid.cell_height;
+        let nearest = |source: &mut Source| source.cell_height(col.round(), row.round());
+        match interpolation {
+            Interpolation::Nearest => nearest(self),
+            // falls back to the nearest cell at the edges of the raster
+            Interpolation::Bilinear => self
+                .bilinear_height(col, row)
+                .or_else(|| nearest(self)),
+        }
+    }
+
+    // Interpolates between the centres of the four cells surrounding the point, avoiding the
+    // stair-step artefacts of using the containing cell's height
+    fn bilinear_height(&mut self, col: f64, row: f64) -> Option<f32> {
+        let (col0, row0) = (col.floor(), row.floor());
+        let (fx, fy) = ((col - col0) as f32, (row - row0) as f32);
+        let top_left = self.cell_height(col0, row0)?;
+        let top_right = self.cell_height(col0 + 1.0, row0)?;
+        let bottom_left = self.cell_height(col0, row0 + 1.0)?;
+        let bottom_right = self.cell_height(col0 + 1.0, row0 + 1.0)?;
+
+        let top = top_left + (top_right - top_left) * fx;
+        let bottom = bottom_left + (bottom_right - bottom_left) * fx;
+        Some(top + (bottom - top) * fy)
+    }
+
+    fn cell_height(&mut self, col: f64, row: f64) -> Option<f32> {
+        if col < 0.0 || row < 0.0 || col >= self.grid.cols as f64 || row >= self.grid.rows as f64 {
+            return None;
+        }
+        if self.reader.is_none() {
+            self.reader = Some(match self.format {
+                Format::GeoTiff => Reader::GeoTiff(GeoTiffElevation::new(BufReader::new(
+                    File::open(&self.path).ok()?,
+                ))),
+                Format::Hgt => Reader::Hgt(load_hgt(&self.path).ok()?),
+            });
+        }
+        match self.reader.as_mut().unwrap() {
+            Reader::GeoTiff(elevation) => {
+                let lon = self.grid.first_x + col * self.grid.cell_width;
+                let lat = self.grid.first_y - row * self.grid.cell_height;
+                let height = elevation.get_height_for_lon_lat(lon as f32, lat as f32)?;
+                let is_nodata = match self.nodata {
+                    Some(nodata) if nodata.is_nan() => height.is_nan(),
+                    Some(nodata) => height == nodata,
+                    None => false,
+                };
+                (!is_nodata).then_some(height)
+            }
+            Reader::Hgt(heights) => {
+                let height = heights[row as usize * self.grid.cols + col as usize];
+                // -32768 marks voids
+                (height != i16::MIN).then_some(height as f32)
+            }
+        }
+    }
+}
+
+impl Grid {
+    fn contains(&self, lon: f64, lat: f64) -> bool {
+        let min_x = self.first_x - self.cell_width / 2.0;
+        let max_y = self.first_y + self.cell_height / 2.0;
+        lon >= min_x
+            && lon < min_x + self.cols as f64 * self.cell_width
+            && lat <= max_y
+            && lat > max_y - self.rows as f64 * self.cell_height
+    }
+}
+
+// Assumes the raster is north-up with pixels covering areas, as in the UK 50m DEM. Also returns
+// the NoData value GDAL records as text, if any.
+fn read_geotiff_grid(path: &str) -> Result<(Grid, Option<f32>)> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+    let (cols, rows) = decoder.dimensions()?;
+    let pixel_scale = decoder.get_tag_f64_vec(Tag::ModelPixelScaleTag)?;
+    // raster (i, j) maps to (x, y)
+    let tiepoint = decoder.get_tag_f64_vec(Tag::ModelTiepointTag)?;
+    let origin_x = tiepoint[3] - tiepoint[0] * pixel_scale[0];
+    let origin_y = tiepoint[4] + tiepoint[1] * pixel_scale[1];
+    let nodata = match decoder.find_tag(Tag::GdalNodata)? {
+        Some(value) => value
+            .into_string()?
+            .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+            .parse::<f32>()
+            .ok(),
+        None => None,
+    };
+    let grid = Grid {
+        first_x: origin_x + pixel_scale[0] / 2.0,
+        first_y: origin_y - pixel_scale[1] / 2.0,
+        cell_width: pixel_scale[0],
+        cell_height: pixel_scale[1],
+        cols: cols as usize,
+        rows: rows as usize,
+    };
+    Ok((grid, nodata))
+}
+
+// SRTM tiles are named after their south west corner, e.g. N51W002.hgt, and hold a square grid of
+// samples (1201 for 3 arc-second, 3601 for 1 arc-second) with the edges shared between tiles
+fn read_hgt_grid(path: &str) -> Result<Grid> {
+    let name = Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_uppercase())
+        .unwrap_or_default();
+    let parse = |hemisphere: &str, degrees: &str, negative: char| -> Result<f64> {
+        let degrees: f64 = degrees.parse()?;
+        Ok(if hemisphere.starts_with(negative) { -degrees } else { degrees })
+    };
+    if name.len() < 7 {
+        anyhow::bail!("Can't read the tile position from the name of {path}");
+    }
+    let lat = parse(&name[0..1], &name[1..3], 'S')?;
+    let lon = parse(&name[3..4], &name[4..7], 'W')?;
+
+    let samples = ((fs_err::metadata(path)?.len() / 2) as f64).sqrt() as usize;
+    if samples < 2 {
+        anyhow::bail!("{path} is too small to be an .hgt tile");
+    }
+    let spacing = 1.0 / (samples - 1) as f64;
+    Ok(Grid {
+        first_x: lon,
+        first_y: lat + 1.0,
+        cell_width: spacing,
+        cell_height: spacing,
+        cols: samples,
+        rows: samples,
+    })
+}
+
+// Reads a whole .hgt tile of big-endian signed heights in metres, once per run. Reading each
+// sample from the file would cost a seek and a read per cell, four per bilinear lookup.
+fn load_hgt(path: &str) -> Result<Arc<Vec<i16>>> {
+    let mut tiles = HGT_TILES.get_or_init(Default::default).lock().unwrap();
+    if let Some(heights) = tiles.get(path) {
+        return Ok(heights.clone());
+    }
+    let heights: Arc<Vec<i16>> = Arc::new(
+        fs_err::read(path)?
+            .chunks_exact(2)
+            .map(|bytes| i16::from_be_bytes([bytes[0], bytes[1]]))
+            .collect(),
+    );
+    tiles.insert(path.to_string(), heights.clone());
+    Ok(heights)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes a 3x3 sample tile with the given name into a new temporary directory
+    fn write_tile(name: &str, heights: [i16; 9]) -> String {
+        let dir = std::env::temp_dir().join(format!("hgt_{}_{name}", std::process::id()));
+        fs_err::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{name}.hgt")).to_string_lossy().to_string();
+        let bytes: Vec<u8> = heights.iter().flat_map(|height| height.to_be_bytes()).collect();
+        fs_err::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn hgt_grid_from_name() {
+        let north_west = write_tile("N51W002", [0; 9]);
+        let grid = read_hgt_grid(&north_west).unwrap();
+        assert_eq!((grid.first_x, grid.first_y), (-2.0, 52.0));
+        assert_eq!((grid.cols, grid.rows), (3, 3));
+        assert_eq!((grid.cell_width, grid.cell_height), (0.5, 0.5));
+
+        let south_east = write_tile("S01E010", [0; 9]);
+        let grid = read_hgt_grid(&south_east).unwrap();
+        assert_eq!((grid.first_x, grid.first_y), (10.0, 0.0));
+        assert!(grid.contains(10.2, -0.2));
+        assert!(!grid.contains(10.2, 0.5));
+
+        let unnamed = write_tile("tile", [0; 9]);
+        assert!(read_hgt_grid(&unnamed).is_err());
+        for path in [north_west, south_east, unnamed] {
+            fs_err::remove_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn hgt_heights() {
+        // rows run from north to south, with a void in the south east corner
+        let path = write_tile("N51E000", [10, 20, 30, 40, 50, 60, 70, 80, i16::MIN]);
+        let mut source = Source::new(path.clone(), 0).unwrap();
+        assert_eq!(source.height(0.0, 52.0, Interpolation::Nearest), Some(10.0));
+        assert_eq!(source.height(0.5, 51.5, Interpolation::Nearest), Some(50.0));
+        assert_eq!(source.height(0.25, 51.75, Interpolation::Bilinear), Some(30.0));
+        assert_eq!(source.height(1.0, 51.0, Interpolation::Nearest), None);
+        fs_err::remove_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+    }
+}
@@ -1,3 +1,4 @@
+use crate::report::Report;
 use crate::*;
 use std::collections::{HashMap, HashSet};
 
@@ -5,14 +6,31 @@ use geo::{Coord, LineString};
 use indicatif::{ProgressBar, ProgressStyle};
 use osm_reader::{Element, NodeID, WayID};
 
+struct Way {
+    id: WayID,
+    node_ids: Vec<NodeID>,
+    highway: String,
+    forward: bool,
+    backward: bool,
+    follows_terrain: bool,
+    layer: i32,
+    roundabout: bool,
+    incline: Option<f32>,
+    speed: f32,
+}
+
 pub fn process(
     osm_paths: Vec<&str>,
     settings: &Settings,
-) -> Result<(HashMap<i64, (usize, Coord)>, Vec<Edge>)> {
-    let (node_mapping, ways) = scrape_osm(osm_paths, &settings)?;
-    let edges: Vec<Edge> = split_ways_into_edges(&node_mapping, ways);
+    report: &mut Report,
+) -> Result<(HashMap<i64, (usize, Coord)>, Vec<Edge>, HashMap<i64, f32>)> {
+    let (node_mapping, ways, delay_candidates, barrier_nodes, node_eles) =
+        scrape_osm(osm_paths, &settings)?;
+    let node_delays = get_node_delays(delay_candidates, &ways, settings);
+    let ways = remove_missing_nodes(&node_mapping, ways, report);
+    let edges: Vec<Edge> = split_ways_into_edges(&node_mapping, ways, &barrier_nodes, &node_eles);
     let graph_nodes_lookup = get_graph_nodes_lookup(node_mapping, &edges);
-    Ok((graph_nodes_lookup, edges))
+    Ok((graph_nodes_lookup, edges, node_delays))
 }
 
 fn scrape_osm(
@@ -20,10 +38,18 @@ fn scrape_osm(
     settings: &Settings,
 ) -> Result<(
     HashMap<NodeID, Coord>,
-    Vec<(WayID, Vec<NodeID>, bool, bool)>,
+    Vec<Way>,
+    HashMap<NodeID, Vec<usize>>,
+    HashSet<NodeID>,
+    HashMap<NodeID, f32>,
 )> {
     let mut node_mapping: HashMap<NodeID, Coord> = HashMap::new();
-    let mut highways: Vec<(WayID, Vec<NodeID>, bool, bool)> = Vec::new();
+    let mut highways: Vec<Way> = Vec::new();
+    // nodes matching the tags of a delay rule, with the indices of the matching rules
+    let mut delay_candidates: HashMap<NodeID, Vec<usize>> = HashMap::new();
+    let mut barrier_nodes: HashSet<NodeID> = HashSet::new();
+    // heights from ele tags, used at the ends of bridges and tunnels
+    let mut node_eles: HashMap<NodeID, f32> = HashMap::new();
     let mut unique_ways: HashSet<WayID> = HashSet::new();
     for osm_path in osm_paths {
         let mut first_way = true;
@@ -37,14 +63,37 @@ fn scrape_osm(
                 .unwrap(),
         );
         osm_reader::parse(&fs_err::read(osm_path)?, |elem| match elem {
-            Element::Node { id, lon, lat, .. } => {
+            Element::Node { id, lon, lat, tags, .. } => {
                 nodes_progress.inc(1);
                 node_mapping.insert(id, Coord { x: lon, y: lat });
+                if let Some(ele) = tags.get("ele").and_then(|ele| parse_ele(ele)) {
+                    node_eles.insert(id, ele);
+                }
+                let matching_rules: Vec<usize> = settings
+                    .node_delays
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, rule)| rule.tag_pairs.iter().all(|(k, v)| tags.get(k) == Some(v)))
+                    .map(|(idx, _)| idx)
+                    .collect();
+                if !matching_rules.is_empty() {
+                    delay_candidates.insert(id, matching_rules);
+                }
+                if settings
+                    .node_barriers
+                    .iter()
+                    .any(|tag_pairs| tag_pairs.iter().all(|(k, v)| tags.get(k) == Some(v)))
+                {
+                    barrier_nodes.insert(id);
+                }
             }
             Element::Way { id, node_ids, tags, .. } => {
                 if tags.contains_key("highway")
+                    && (settings.highways.is_empty() || settings.highways.contains(&tags["highway"]))
                     // select just ways meeting mode criteria
-                    && settings.tag_pairs.iter().all(|(k, v)| tags.get(k) != Some(v))
+                    && settings.tag_pairs.iter().all(|(k, v)| {
+                        tags.get(k) != Some(v) || has_exception(&tags, (k, v), settings)
+                    })
                     && unique_ways.get(&id).is_none()
                 {
                     // TODO: add oneway tag filtering here
@@ -54,7 +103,18 @@ fn scrape_osm(
                     }
                     ways_progress.inc(1);
                     let (forward, backward) = oneway_access(&tags, &settings);
-                    highways.push((id, node_ids, forward, backward));
+                    highways.push(Way {
+                        id,
+                        node_ids,
+                        highway: tags["highway"].clone(),
+                        forward,
+                        backward,
+                        follows_terrain: follows_terrain(&tags),
+                        layer: layer(&tags),
+                        roundabout: is_roundabout(&tags),
+                        incline: tags.get("incline").and_then(|incline| parse_incline(incline)),
+                        speed: way_speed(&tags, settings),
+                    });
                     unique_ways.insert(id);
                 }
             }
@@ -63,19 +123,150 @@ fn scrape_osm(
         ways_progress.finish();
     }
     
-    Ok((node_mapping, highways))
+    Ok((node_mapping, highways, delay_candidates, barrier_nodes, node_eles))
 }
 
+fn has_exception(
+    tags: &HashMap<String, String>,
+    tag_pair: (&String, &String),
+    settings: &Settings,
+) -> bool {
+    settings
+        .tag_pair_exceptions
+        .iter()
+        .any(|((k, v), (exception_k, exception_v))| {
+            (k, v) == tag_pair && tags.get(exception_k) == Some(exception_v)
+        })
+}
+
+fn way_speed(tags: &HashMap<String, String>, settings: &Settings) -> f32 {
+    // maxspeed=0 or a negative value would give an infinite traversal time
+    let maxspeed = if settings.use_maxspeed {
+        maxspeed::way_maxspeed(tags).filter(|speed| *speed > 0.0)
+    } else {
+        None
+    };
+    let speed = maxspeed
+        .or_else(|| settings.highway_speeds.get(&tags["highway"]).copied())
+        .unwrap_or(settings.speed);
+    settings
+        .speed_factors
+        .iter()
+        .filter(|speed_factor| {
+            speed_factor
+                .tag_pairs
+                .iter()
+                .all(|(k, v)| tags.get(k) == Some(v))
+        })
+        .fold(speed, |speed, speed_factor| speed * speed_factor.factor)
+        .min(settings.max_speed.unwrap_or(f32::INFINITY))
+}
+
+fn get_node_delays(
+    delay_candidates: HashMap<NodeID, Vec<usize>>,
+    ways: &Vec<Way>,
+    settings: &Settings,
+) -> HashMap<i64, f32> {
+    // Highway values of the ways passing through each candidate node
+    let mut node_highways: HashMap<NodeID, HashSet<&str>> = HashMap::new();
+    for way in ways {
+        for node in &way.node_ids {
+            if delay_candidates.contains_key(node) {
+                node_highways
+                    .entry(*node)
+                    .or_insert_with(HashSet::new)
+                    .insert(way.highway.as_str());
+            }
+        }
+    }
+
+    // Where several rules apply to a node only the largest delay is used, so that
+    // e.g. a node tagged both highway=traffic_signals and crossing=traffic_signals
+    // isn't counted twice
+    let mut node_delays: HashMap<i64, f32> = HashMap::new();
+    for (node, rule_indices) in delay_candidates {
+        let Some(highways) = node_highways.get(&node) else {
+            continue;
+        };
+        for idx in rule_indices {
+            let rule = &settings.node_delays[idx];
+            if !rule.on_highways.is_empty()
+                && !rule.on_highways.iter().any(|h| highways.contains(h.as_str()))
+            {
+                continue;
+            }
+            let delay = node_delays.entry(node.0).or_insert(0.0);
+            *delay = delay.max(rule.delay);
+        }
+    }
+    println!("Found {} nodes with delays", node_delays.len());
+    node_delays
+}
+
+// Ways crossing the boundary of the extracts reference nodes that were never read. Such ways are
+// split into the runs of consecutive nodes that are present, and dropped if no run has at least
+// two nodes.
+fn remove_missing_nodes(
+    node_mapping: &HashMap<NodeID, Coord>,
+    ways: Vec<Way>,
+    report: &mut Report,
+) -> Vec<Way> {
+    let mut complete_ways: Vec<Way> = Vec::new();
+    for way in ways {
+        if way.node_ids.iter().all(|node| node_mapping.contains_key(node)) {
+            complete_ways.push(way);
+            continue;
+        }
+        let runs: Vec<Vec<NodeID>> = way
+            .node_ids
+            .split(|node| !node_mapping.contains_key(node))
+            .filter(|run| run.len() > 1)
+            .map(|run| run.to_vec())
+            .collect();
+        if runs.is_empty() {
+            report.missing_nodes.dropped_way_ids.push(way.id.0);
+            continue;
+        }
+        report.missing_nodes.truncated_way_ids.push(way.id.0);
+        for node_ids in runs {
+            complete_ways.push(Way {
+                id: way.id,
+                node_ids,
+                highway: way.highway.clone(),
+                forward: way.forward,
+                backward: way.backward,
+                follows_terrain: way.follows_terrain,
+                layer: way.layer,
+                roundabout: way.roundabout,
+                incline: way.incline,
+                speed: way.speed,
+            });
+        }
+    }
+    report.missing_nodes.truncated_way_count = report.missing_nodes.truncated_way_ids.len();
+    report.missing_nodes.dropped_way_count = report.missing_nodes.dropped_way_ids.len();
+    println!(
+        "Truncated {} and dropped {} ways referencing missing nodes",
+        report.missing_nodes.truncated_way_count, report.missing_nodes.dropped_way_count
+    );
+    complete_ways
+}
+
+// A barrier node blocks the edges passing through it and the edges of ways ending at it, but not
+// edges of other ways that merely meet there, e.g. a raised kerb at the end of a crossing blocks
+// the crossing but not the footway along the road.
 fn split_ways_into_edges(
     node_mapping: &HashMap<NodeID, Coord>,
-    ways: Vec<(WayID, Vec<NodeID>, bool, bool)>,
+    ways: Vec<Way>,
+    barrier_nodes: &HashSet<NodeID>,
+    node_eles: &HashMap<NodeID, f32>,
 ) -> Vec<Edge> {
     println!("Splitting ways into edges");
 
     // Count how many ways reference each node
     let mut node_counter: HashMap<NodeID, usize> = HashMap::new();
-    for (_, node_ids, _, _) in &ways {
-        for node in node_ids {
+    for way in &ways {
+        for node in &way.node_ids {
             *node_counter.entry(*node).or_insert(0) += 1;
         }
     }
@@ -85,36 +276,62 @@ fn split_ways_into_edges(
         "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {human_pos}/{human_len} ({per_sec}, {eta})").unwrap());
     let mut edges: Vec<Edge> = Vec::new();
     let mut edge_id: usize = 0;
-    for (way_id, node_ids, forward, backward) in ways {
+    let mut blocked_edges: usize = 0;
+    for way in ways {
         progress.inc(1);
         let mut pts = Vec::new();
-        let mut start_node = node_ids[0].clone();
+        let mut edge_node_ids = Vec::new();
+        let mut start_node = way.node_ids[0].clone();
+        let mut blocked = barrier_nodes.contains(&start_node);
 
-        let num_nodes = node_ids.len();
-        for (idx, node) in node_ids.into_iter().enumerate() {
+        let num_nodes = way.node_ids.len();
+        for (idx, node) in way.node_ids.into_iter().enumerate() {
             pts.push(node_mapping[&node]);
+            edge_node_ids.push(node.0);
             // Edges start/end at intersections between two ways. The endpoints of the way also
             // count as intersections.
             let is_endpoint =
                 idx == 0 || idx == num_nodes - 1 || *node_counter.get(&node).unwrap() > 1;
+            if (!is_endpoint || idx == num_nodes - 1) && barrier_nodes.contains(&node) {
+                blocked = true;
+            }
             if is_endpoint && pts.len() > 1 {
-                edges.push(Edge {
-                    id: edge_id,
-                    osm_id: way_id.0,
-                    start_node: start_node.0,
-                    end_node: node.0,
-                    linestring: LineString::new(std::mem::take(&mut pts)),
-                    forward: forward,
-                    backward: backward,
-                });
-                edge_id += 1;
+                if blocked {
+                    blocked_edges += 1;
+                    pts.clear();
+                    edge_node_ids.clear();
+                } else {
+                    edges.push(Edge {
+                        id: edge_id,
+                        osm_id: way.id.0,
+                        start_node: start_node.0,
+                        end_node: node.0,
+                        node_ids: std::mem::take(&mut edge_node_ids),
+                        linestring: LineString::new(std::mem::take(&mut pts)),
+                        forward: way.forward,
+                        backward: way.backward,
+                        follows_terrain: way.follows_terrain,
+                        layer: way.layer,
+                        roundabout: way.roundabout,
+                        incline: way.incline,
+                        start_ele: node_eles.get(&start_node).copied(),
+                        end_ele: node_eles.get(&node).copied(),
+                        speed: way.speed,
+                    });
+                    edge_id += 1;
+                }
+                blocked = false;
                 start_node = node;
                 // Start the next edge
                 pts.push(node_mapping[&node]);
+                edge_node_ids.push(node.0);
             }
         }
     }
     progress.finish();
+    if !barrier_nodes.is_empty() {
+        println!("Removed {blocked_edges} edges blocked by barriers");
+    }
     edges
 }
 
@@ -143,14 +360,47 @@ fn get_graph_nodes_lookup(
     graph_nodes_lookup
 }
 
+// Reassigns dense graph node ids after edges have been merged or removed, dropping nodes no longer
+// at the end of any edge
+pub fn renumber_graph_nodes(
+    graph_nodes_lookup: &HashMap<i64, (usize, Coord)>,
+    edges: &Vec<Edge>,
+) -> HashMap<i64, (usize, Coord)> {
+    let mut renumbered: HashMap<i64, (usize, Coord)> = HashMap::new();
+    let mut graph_node_id: usize = 0;
+    for edge in edges {
+        for node in [edge.start_node, edge.end_node] {
+            if !renumbered.contains_key(&node) {
+                renumbered.insert(node, (graph_node_id, graph_nodes_lookup[&node].1));
+                graph_node_id += 1;
+            }
+        }
+    }
+    renumbered
+}
+
 fn oneway_access(tags: &HashMap<String, String>, settings: &Settings) -> (bool, bool) {
-    let forward = true;
+    let mut forward = true;
     let mut backward = true;
-    if settings.mode != "walk" {
+    if settings.mode == "car" {
+        // roundabouts and motorways are one way without needing to be tagged
+        let implied_oneway =
+            is_roundabout(tags) || tags.get("highway") == Some(&"motorway".to_string());
+        match tags.get("oneway").map(|v| v.as_str()) {
+            Some("yes" | "true" | "1") => backward = false,
+            Some("-1" | "reverse") => forward = false,
+            Some("no" | "false" | "0") => {}
+            _ => {
+                if implied_oneway {
+                    backward = false;
+                }
+            }
+        }
+    } else if !["walk", "wheelchair"].contains(&settings.mode.as_str()) {
         if tags.get("oneway") == Some(&"yes".to_string()) {
             backward = false;
         }
-        if settings.mode == "cycling" {
+        if ["cycling", "ebike"].contains(&settings.mode.as_str()) {
             if tags.get("oneway:bicycle") == Some(&"no".to_string()) {
                 backward = true;
             }
@@ -158,3 +408,152 @@ fn oneway_access(tags: &HashMap<String, String>, settings: &Settings) -> (bool,
     }
     (forward, backward)
 }
+
+fn follows_terrain(tags: &HashMap<String, String>) -> bool {
+    let bridge = tags.get("bridge").map_or(false, |v| v != "no");
+    // building passages run at ground level through a building
+    let tunnel = tags
+        .get("tunnel")
+        .map_or(false, |v| v != "no" && v != "building_passage");
+    !bridge && !tunnel && layer(tags) == 0
+}
+
+fn layer(tags: &HashMap<String, String>) -> i32 {
+    tags.get("layer")
+        .and_then(|v| v.trim().parse::<i32>().ok())
+        .unwrap_or(0)
+}
+
+fn is_roundabout(tags: &HashMap<String, String>) -> bool {
+    matches!(
+        tags.get("junction").map(|v| v.as_str()),
+        Some("roundabout" | "circular")
+    )
+}
+
+// Parses heights such as "52", "52.5" or "52 m". Heights in other units are ignored.
+fn parse_ele(ele: &str) -> Option<f32> {
+    let ele = ele.trim();
+    ele.strip_suffix('m')
+        .unwrap_or(ele)
+        .trim()
+        .parse::<f32>()
+        .ok()
+        .filter(|ele| ele.is_finite())
+}
+
+// Parses numeric incline values such as "5%", "-10 %" or "3°" into a gradient. Values like "up"
+// or "down" carry no magnitude and are ignored.
+fn parse_incline(incline: &str) -> Option<f32> {
+    let incline = incline.trim();
+    if let Some(degrees) = incline.strip_suffix('°') {
+        let degrees: f32 = degrees.trim().parse().ok()?;
+        return Some(degrees.to_radians().tan());
+    }
+    let percent: f32 = incline.trim_end_matches('%').trim().parse().ok()?;
+    Some(percent / 100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn way(id: i64, node_ids: Vec<i64>, highway: &str) -> Way {
+        Way {
+            id: WayID(id),
+            node_ids: node_ids.into_iter().map(NodeID).collect(),
+            highway: highway.to_string(),
+            forward: true,
+            backward: true,
+            follows_terrain: true,
+            layer: 0,
+            roundabout: false,
+            incline: None,
+            speed: 1.0,
+        }
+    }
+
+    fn settings(node_delays: &str) -> Settings {
+        serde_json::from_str(&format!(
+            r#"{{"mode": "walk", "tag_pairs": [], "speed": 1.33, "ascention_speed": 6.0,
+                "descent_speed": 0.0, "node_delays": {node_delays}}}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn largest_delay_of_matching_rules() {
+        let settings = settings(
+            r#"[{"tag_pairs": [["highway", "traffic_signals"]], "delay": 20.0},
+                {"tag_pairs": [["crossing", "traffic_signals"]], "delay": 30.0},
+                {"tag_pairs": [["highway", "crossing"]], "delay": 5.0}]"#,
+        );
+        let ways = vec![way(100, vec![1, 2, 3], "footway")];
+        let delay_candidates = HashMap::from([(NodeID(2), vec![0, 1]), (NodeID(3), vec![2])]);
+        let node_delays = get_node_delays(delay_candidates, &ways, &settings);
+        assert_eq!(node_delays, HashMap::from([(2, 30.0), (3, 5.0)]));
+    }
+
+    #[test]
+    fn delays_only_on_the_given_highways() {
+        let settings = settings(
+            r#"[{"tag_pairs": [["highway", "traffic_signals"]], "on_highways": ["primary"],
+                 "delay": 20.0}]"#,
+        );
+        // node 2 is shared by the primary road and a footway, node 5 is only on the footway and
+        // node 7 isn't on any way read
+        let ways = vec![
+            way(100, vec![1, 2, 3], "primary"),
+            way(101, vec![4, 2, 5], "footway"),
+        ];
+        let delay_candidates = HashMap::from([
+            (NodeID(2), vec![0]),
+            (NodeID(5), vec![0]),
+            (NodeID(7), vec![0]),
+        ]);
+        let node_delays = get_node_delays(delay_candidates, &ways, &settings);
+        assert_eq!(node_delays, HashMap::from([(2, 20.0)]));
+    }
+
+    #[test]
+    fn ways_split_around_missing_nodes() {
+        // nodes 3 and 6 are outside the extracts
+        let node_mapping: HashMap<NodeID, Coord> = [1, 2, 4, 5, 7, 8, 9]
+            .into_iter()
+            .map(|node| (NodeID(node), Coord { x: node as f64, y: 0.0 }))
+            .collect();
+        let ways = vec![
+            way(100, vec![1, 2, 3, 4, 5, 6, 7], "footway"), // runs 1-2 and 4-5, 7 is left alone
+            way(101, vec![3, 8, 6], "footway"),              // only node 8 is present
+            way(102, vec![8, 9], "footway"),                 // complete
+        ];
+        let mut report = Report::default();
+        let ways = remove_missing_nodes(&node_mapping, ways, &mut report);
+
+        let runs: Vec<(i64, Vec<i64>)> = ways
+            .iter()
+            .map(|way| (way.id.0, way.node_ids.iter().map(|node| node.0).collect()))
+            .collect();
+        assert_eq!(runs, vec![(100, vec![1, 2]), (100, vec![4, 5]), (102, vec![8, 9])]);
+        assert_eq!(report.missing_nodes.truncated_way_ids, vec![100]);
+        assert_eq!(report.missing_nodes.dropped_way_ids, vec![101]);
+        assert_eq!(report.missing_nodes.truncated_way_count, 1);
+        assert_eq!(report.missing_nodes.dropped_way_count, 1);
+    }
+
+    #[test]
+    fn zero_maxspeed_falls_back_to_the_highway_speed() {
+        let settings: Settings = serde_json::from_str(
+            r#"{"mode": "car", "tag_pairs": [], "speed": 13.41, "ascention_speed": 0.0,
+                "descent_speed": 0.0, "use_maxspeed": true, "highway_speeds": {"primary": 22.35}}"#,
+        )
+        .unwrap();
+        for maxspeed in ["0", "-20"] {
+            let tags = HashMap::from([
+                ("highway".to_string(), "primary".to_string()),
+                ("maxspeed".to_string(), maxspeed.to_string()),
+            ]);
+            assert_eq!(way_speed(&tags, &settings), 22.35);
+        }
+    }
+}
@@ -0,0 +1,562 @@
+use crate::*;
+use crate::dem::Dem;
+use graph_from_pbf::Interpolation;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use fs_err::File;
+use geo::{Coord, HaversineLength, Line};
+use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Heights read from the DEM, shared between modes so each point is only sampled once per run, and
+// optionally saved to disk for later runs on the same DEM. Heights are kept per OSM node, and per
+// OSM way segment (pair of consecutive nodes) for the points sampled between its nodes. The keys
+// include the positions of the nodes, so a node moved since the cache was made, e.g. in a later
+// extract, is sampled again rather than given the height of its old position.
+pub struct ElevationCache {
+    dem_path: String,
+    dem_files: Vec<DemFile>,
+    path: Option<String>,
+    node_heights: NodeHeights,
+    segment_heights: SegmentHeights,
+    modified: bool,
+}
+
+type NodeHeights = HashMap<NodeKey, Option<f32>>;
+type SegmentHeights = HashMap<SegmentKey, Vec<Option<f32>>>;
+
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
+struct NodeKey {
+    node: i64,
+    position: Position,
+    interpolation: Interpolation,
+}
+
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
+struct SegmentKey {
+    start_node: i64, // the lower node id, heights are ordered from start_node to end_node
+    end_node: i64,
+    start: Position,
+    end: Position,
+    sample_spacing: u32, // bits of the f32 spacing in metres
+    interpolation: Interpolation,
+}
+
+// A position in the 1e-7 degree units OSM stores coordinates in
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
+struct Position {
+    lon: i32,
+    lat: i32,
+}
+
+impl Position {
+    fn new(coord: Coord) -> Position {
+        Position {
+            lon: (coord.x * 1e7).round() as i32,
+            lat: (coord.y * 1e7).round() as i32,
+        }
+    }
+}
+
+// A raster the DEM reads from, to tell whether it changed since the cache was made
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+struct DemFile {
+    path: String,
+    size: u64,     // bytes
+    modified: u64, // unix seconds
+}
+
+// Binary form of the cache. All values are little-endian:
+//   magic b"ELEVCACH", format version u32, header length u64, header as JSON
+//   node count u64, then per node: node i64, lon i32, lat i32, interpolation u8, height f32
+//   segment count u64, then per segment: start_node i64, end_node i64, start lon i32, start lat i32,
+//     end lon i32, end lat i32, sample_spacing u32, interpolation u8, height count u32,
+//     heights f32 each
+// Heights outside the DEM are stored as NaN.
+const CACHE_MAGIC: &[u8; 8] = b"ELEVCACH";
+const CACHE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct CacheHeader {
+    dem_path: String,
+    dem_files: Vec<DemFile>,
+}
+
+impl ElevationCache {
+    // Starts from the cache file at path if there is one for the same DEM, with the same rasters
+    // unchanged since it was made
+    pub fn new(dem_path: &str, path: Option<&str>) -> Result<ElevationCache> {
+        let mut cache = ElevationCache {
+            dem_path: dem_path.to_string(),
+            dem_files: dem_files(dem_path)?,
+            path: path.map(|path| path.to_string()),
+            node_heights: HashMap::new(),
+            segment_heights: HashMap::new(),
+            modified: false,
+        };
+        let Some(path) = path else {
+            return Ok(cache);
+        };
+        if !Path::new(path).exists() {
+            return Ok(cache);
+        }
+        // a cache that can't be used is replaced when the run finishes, rather than failing every
+        // run until it's deleted
+        match read_cache_file(path, dem_path, &cache.dem_files) {
+            Ok((node_heights, segment_heights)) => {
+                cache.node_heights = node_heights;
+                cache.segment_heights = segment_heights;
+                println!(
+                    "Loaded {} node and {} segment heights from {path}",
+                    cache.node_heights.len(),
+                    cache.segment_heights.len()
+                );
+            }
+            Err(err) => println!("Ignoring elevation cache {path}, {err}"),
+        }
+        Ok(cache)
+    }
+
+    // A cache holding only the given node heights, without a DEM to sample more
+    #[cfg(test)]
+    pub fn from_node_heights(node_heights: Vec<(i64, Coord, Option<f32>)>) -> ElevationCache {
+        ElevationCache {
+            dem_path: String::new(),
+            dem_files: Vec::new(),
+            path: None,
+            node_heights: node_heights
+                .into_iter()
+                .map(|(node, coord, height)| (node_key(node, coord, Interpolation::default()), height))
+                .collect(),
+            segment_heights: HashMap::new(),
+            modified: false,
+        }
+    }
+
+    // Samples the DEM for the nodes and segments of the edges that aren't cached yet. Edges that
+    // don't follow the terrain only need the heights at their ends.
+    pub fn fill(&mut self, edges: &Vec<Edge>, settings: &Settings) {
+        let interpolation = settings.dem_interpolation;
+        let mut missing_nodes: HashMap<NodeKey, Coord> = HashMap::new();
+        let mut missing_segments: HashMap<SegmentKey, Line> = HashMap::new();
+        for edge in edges {
+            let coords = &edge.linestring.0;
+            let vertices: Vec<usize> = if edge.follows_terrain {
+                (0..coords.len()).collect()
+            } else {
+                vec![0, coords.len() - 1]
+            };
+            for idx in vertices {
+                let key = node_key(edge.node_ids[idx], coords[idx], interpolation);
+                if !self.node_heights.contains_key(&key) {
+                    missing_nodes.insert(key, coords[idx]);
+                }
+            }
+            let Some(spacing) = settings.elevation_sample_spacing else {
+                continue;
+            };
+            if !edge.follows_terrain {
+                continue;
+            }
+            for (idx, nodes) in edge.node_ids.windows(2).enumerate() {
+                let line = Line::new(coords[idx], coords[idx + 1]);
+                let (key, reversed) = segment_key(nodes[0], nodes[1], line, spacing, interpolation);
+                if !self.segment_heights.contains_key(&key) {
+                    let line = if reversed {
+                        Line::new(line.end, line.start)
+                    } else {
+                        line
+                    };
+                    missing_segments.insert(key, line);
+                }
+            }
+        }
+        if missing_nodes.is_empty() && missing_segments.is_empty() {
+            println!("All heights are cached");
+            return;
+        }
+
+        println!(
+            "Sampling heights for {} nodes and {} segments",
+            missing_nodes.len(),
+            missing_segments.len()
+        );
+        let progress = ProgressBar::new((missing_nodes.len() + missing_segments.len()) as u64)
+            .with_style(ProgressStyle::with_template(
+                "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {human_pos}/{human_len} ({per_sec}, {eta})").unwrap());
+        let dem_path = self.dem_path.as_str();
+        let node_heights: Vec<(NodeKey, Option<f32>)> = missing_nodes
+            .into_par_iter()
+            .progress_with(progress.clone())
+            .map(|(key, coord)| {
+                with_dem(dem_path, interpolation, |dem| (key, dem.height(coord.x, coord.y)))
+            })
+            .collect();
+        let segment_heights: Vec<(SegmentKey, Vec<Option<f32>>)> = missing_segments
+            .into_par_iter()
+            .progress_with(progress)
+            .map(|(key, line)| {
+                let spacing = f32::from_bits(key.sample_spacing);
+                with_dem(dem_path, interpolation, |dem| {
+                    let heights = interior_points(line, spacing)
+                        .into_iter()
+                        .map(|coord| dem.height(coord.x, coord.y))
+                        .collect();
+                    (key, heights)
+                })
+            })
+            .collect();
+
+        self.node_heights.extend(node_heights);
+        self.segment_heights.extend(segment_heights);
+        self.modified = true;
+    }
+
+    pub fn node_height(&self, node: i64, coord: Coord, interpolation: Interpolation) -> Option<f32> {
+        self.node_heights
+            .get(&node_key(node, coord, interpolation))
+            .copied()
+            .flatten()
+    }
+
+    // Heights of the points sampled between two consecutive nodes, ordered from start_node. Empty
+    // without a sample spacing, as only the nodes are sampled then.
+    pub fn segment_heights(
+        &self,
+        start_node: i64,
+        end_node: i64,
+        line: Line,
+        sample_spacing: Option<f32>,
+        interpolation: Interpolation,
+    ) -> Vec<Option<f32>> {
+        let Some(spacing) = sample_spacing else {
+            return Vec::new();
+        };
+        let (key, reversed) = segment_key(start_node, end_node, line, spacing, interpolation);
+        let mut heights = self.segment_heights.get(&key).cloned().unwrap_or_default();
+        if reversed {
+            heights.reverse();
+        }
+        heights
+    }
+
+    // Writes the cache once all modes have filled it
+    pub fn save(&mut self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.modified {
+            return Ok(());
+        }
+        let header = serde_json::to_vec(&CacheHeader {
+            dem_path: self.dem_path.clone(),
+            dem_files: self.dem_files.clone(),
+        })?;
+        // written beside the cache and moved into place, so a run killed while saving leaves the
+        // previous cache intact
+        let temp_path = format!("{path}.tmp");
+        let mut out = BufWriter::new(File::create(&temp_path)?);
+        out.write_all(CACHE_MAGIC)?;
+        out.write_all(&CACHE_VERSION.to_le_bytes())?;
+        out.write_all(&(header.len() as u64).to_le_bytes())?;
+        out.write_all(&header)?;
+
+        out.write_all(&(self.node_heights.len() as u64).to_le_bytes())?;
+        for (key, height) in &self.node_heights {
+            out.write_all(&key.node.to_le_bytes())?;
+            write_position(&mut out, key.position)?;
+            out.write_all(&[interpolation_code(key.interpolation)])?;
+            out.write_all(&height.unwrap_or(f32::NAN).to_le_bytes())?;
+        }
+        out.write_all(&(self.segment_heights.len() as u64).to_le_bytes())?;
+        for (key, heights) in &self.segment_heights {
+            out.write_all(&key.start_node.to_le_bytes())?;
+            out.write_all(&key.end_node.to_le_bytes())?;
+            write_position(&mut out, key.start)?;
+            write_position(&mut out, key.end)?;
+            out.write_all(&key.sample_spacing.to_le_bytes())?;
+            out.write_all(&[interpolation_code(key.interpolation)])?;
+            out.write_all(&u32::try_from(heights.len())?.to_le_bytes())?;
+            for height in heights {
+                out.write_all(&height.unwrap_or(f32::NAN).to_le_bytes())?;
+            }
+        }
+        out.flush()?;
+        drop(out);
+        fs_err::rename(&temp_path, path)?;
+        println!("Saved elevation cache to {path}");
+        self.modified = false;
+        Ok(())
+    }
+}
+
+// Limits on the sizes read from a cache file, so a corrupt one can't cause huge allocations
+const MAX_HEADER_LEN: u64 = 1 << 24;
+const NODE_RECORD_LEN: u64 = 21;
+const MIN_SEGMENT_RECORD_LEN: u64 = 41;
+
+// Reads the heights of a cache file made from the same DEM files, failing with the reason it can't
+// be used otherwise
+fn read_cache_file(
+    path: &str,
+    dem_path: &str,
+    dem_files: &[DemFile],
+) -> Result<(NodeHeights, SegmentHeights)> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut magic = [0u8; 8];
+    if reader.read_exact(&mut magic).is_err() || &magic != CACHE_MAGIC {
+        anyhow::bail!("it isn't in the current format");
+    }
+    let version = u32::from_le_bytes(read_bytes(&mut reader)?);
+    if version != CACHE_VERSION {
+        anyhow::bail!("it has format version {version}");
+    }
+    let header_len = u64::from_le_bytes(read_bytes(&mut reader)?);
+    if header_len > MAX_HEADER_LEN.min(file_len) {
+        anyhow::bail!("its header is {header_len} bytes long");
+    }
+    let mut header = vec![0u8; header_len as usize];
+    reader.read_exact(&mut header)?;
+    let header: CacheHeader = serde_json::from_slice(&header)?;
+    if header.dem_path != dem_path {
+        anyhow::bail!("made from {}", header.dem_path);
+    }
+    if header.dem_files != dem_files {
+        anyhow::bail!("the DEM files changed since it was made");
+    }
+
+    let node_count = u64::from_le_bytes(read_bytes(&mut reader)?);
+    if node_count > file_len / NODE_RECORD_LEN {
+        anyhow::bail!("it claims {node_count} node heights");
+    }
+    let mut node_heights = HashMap::new();
+    for _ in 0..node_count {
+        let key = NodeKey {
+            node: i64::from_le_bytes(read_bytes(&mut reader)?),
+            position: read_position(&mut reader)?,
+            interpolation: read_interpolation(&mut reader)?,
+        };
+        node_heights.insert(key, read_height(&mut reader)?);
+    }
+    let segment_count = u64::from_le_bytes(read_bytes(&mut reader)?);
+    if segment_count > file_len / MIN_SEGMENT_RECORD_LEN {
+        anyhow::bail!("it claims {segment_count} segments");
+    }
+    let mut segment_heights = HashMap::new();
+    for _ in 0..segment_count {
+        let key = SegmentKey {
+            start_node: i64::from_le_bytes(read_bytes(&mut reader)?),
+            end_node: i64::from_le_bytes(read_bytes(&mut reader)?),
+            start: read_position(&mut reader)?,
+            end: read_position(&mut reader)?,
+            sample_spacing: u32::from_le_bytes(read_bytes(&mut reader)?),
+            interpolation: read_interpolation(&mut reader)?,
+        };
+        let height_count = u32::from_le_bytes(read_bytes(&mut reader)?);
+        if height_count as u64 > file_len / 4 {
+            anyhow::bail!("it claims {height_count} heights for one segment");
+        }
+        let heights = (0..height_count)
+            .map(|_| read_height(&mut reader))
+            .collect::<Result<Vec<Option<f32>>>>()?;
+        segment_heights.insert(key, heights);
+    }
+    Ok((node_heights, segment_heights))
+}
+
+fn dem_files(dem_path: &str) -> Result<Vec<DemFile>> {
+    let mut files = Vec::new();
+    for path in Dem::open(dem_path, Interpolation::Nearest)?.source_paths() {
+        let metadata = fs_err::metadata(path)?;
+        files.push(DemFile {
+            path: path.to_string(),
+            size: metadata.len(),
+            modified: metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs(),
+        });
+    }
+    Ok(files)
+}
+
+fn node_key(node: i64, coord: Coord, interpolation: Interpolation) -> NodeKey {
+    NodeKey {
+        node,
+        position: Position::new(coord),
+        interpolation,
+    }
+}
+
+// Segments are stored in one direction only, so both directions of a way share their heights
+fn segment_key(
+    start_node: i64,
+    end_node: i64,
+    line: Line,
+    sample_spacing: f32,
+    interpolation: Interpolation,
+) -> (SegmentKey, bool) {
+    let reversed = start_node > end_node;
+    let ((start_node, start), (end_node, end)) = if reversed {
+        ((end_node, line.end), (start_node, line.start))
+    } else {
+        ((start_node, line.start), (end_node, line.end))
+    };
+    let key = SegmentKey {
+        start_node,
+        end_node,
+        start: Position::new(start),
+        end: Position::new(end),
+        sample_spacing: sample_spacing.to_bits(),
+        interpolation,
+    };
+    (key, reversed)
+}
+
+fn interpolation_code(interpolation: Interpolation) -> u8 {
+    match interpolation {
+        Interpolation::Nearest => 0,
+        Interpolation::Bilinear => 1,
+    }
+}
+
+fn read_interpolation(reader: &mut impl Read) -> Result<Interpolation> {
+    match read_bytes::<1>(reader)?[0] {
+        0 => Ok(Interpolation::Nearest),
+        1 => Ok(Interpolation::Bilinear),
+        code => anyhow::bail!("it has an unknown interpolation {code}"),
+    }
+}
+
+fn write_position(out: &mut impl Write, position: Position) -> Result<()> {
+    out.write_all(&position.lon.to_le_bytes())?;
+    out.write_all(&position.lat.to_le_bytes())?;
+    Ok(())
+}
+
+fn read_position(reader: &mut impl Read) -> Result<Position> {
+    Ok(Position {
+        lon: i32::from_le_bytes(read_bytes(reader)?),
+        lat: i32::from_le_bytes(read_bytes(reader)?),
+    })
+}
+
+fn read_height(reader: &mut impl Read) -> Result<Option<f32>> {
+    let height = f32::from_le_bytes(read_bytes(reader)?);
+    Ok((!height.is_nan()).then_some(height))
+}
+
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Evenly spaced points strictly between the ends of a segment longer than the spacing
+fn interior_points(line: Line, sample_spacing: f32) -> Vec<Coord> {
+    let length = line.haversine_length() as f32;
+    if sample_spacing <= 0.0 {
+        return Vec::new();
+    }
+    let num_steps = (length / sample_spacing).ceil().max(1.0) as usize;
+    (1..num_steps)
+        .map(|step| {
+            let fraction = step as f64 / num_steps as f64;
+            Coord {
+                x: line.start.x + (line.end.x - line.start.x) * fraction,
+                y: line.start.y + (line.end.y - line.start.y) * fraction,
+            }
+        })
+        .collect()
+}
+
+// The rayon threads outlive each mode, so the DEM is reopened if the interpolation changed
+fn with_dem<T>(dem_path: &str, interpolation: Interpolation, f: impl FnOnce(&mut Dem) -> T) -> T {
+    thread_local!(static DEM: RefCell<Option<(Interpolation, Dem)>> = const { RefCell::new(None) });
+    DEM.with(|dem_cell| {
+        let mut dem = dem_cell.borrow_mut();
+        if dem.as_ref().map(|(interpolation, _)| *interpolation) != Some(interpolation) {
+            *dem = Some((interpolation, Dem::open(dem_path, interpolation).unwrap()));
+        }
+        f(&mut dem.as_mut().unwrap().1)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saved_cache(path: &str) -> ElevationCache {
+        let mut cache = ElevationCache::from_node_heights(vec![
+            (1, Coord { x: -1.5, y: 51.2 }, Some(12.5)),
+            (2, Coord { x: -1.4, y: 51.3 }, None),
+        ]);
+        cache.dem_path = "dem.tif".to_string();
+        cache.path = Some(path.to_string());
+        let line = Line::new(Coord { x: -1.4, y: 51.3 }, Coord { x: -1.5, y: 51.2 });
+        let (key, _) = segment_key(2, 1, line, 25.0, Interpolation::Bilinear);
+        cache.segment_heights.insert(key, vec![Some(13.0), None, Some(14.5)]);
+        cache.modified = true;
+        cache.save().unwrap();
+        cache
+    }
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{name}_{}.bin", std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = temp_path("elevation_cache_round_trip");
+        let cache = saved_cache(&path);
+        assert!(!Path::new(&format!("{path}.tmp")).exists());
+        let (node_heights, segment_heights) = read_cache_file(&path, "dem.tif", &Vec::new()).unwrap();
+        fs_err::remove_file(&path).unwrap();
+        assert_eq!(node_heights, cache.node_heights);
+        assert_eq!(segment_heights, cache.segment_heights);
+
+        let loaded = ElevationCache {
+            node_heights,
+            segment_heights,
+            ..ElevationCache::from_node_heights(Vec::new())
+        };
+        let interpolation = Interpolation::default();
+        assert_eq!(loaded.node_height(1, Coord { x: -1.5, y: 51.2 }, interpolation), Some(12.5));
+        // the node has moved since the cache was made
+        assert_eq!(loaded.node_height(1, Coord { x: -1.5, y: 51.21 }, interpolation), None);
+        // heights are stored from the lower node id and reversed for the other direction
+        let line = Line::new(Coord { x: -1.5, y: 51.2 }, Coord { x: -1.4, y: 51.3 });
+        assert_eq!(
+            loaded.segment_heights(1, 2, line, Some(25.0), Interpolation::Bilinear),
+            vec![Some(13.0), None, Some(14.5)]
+        );
+        let line = Line::new(line.end, line.start);
+        assert_eq!(
+            loaded.segment_heights(2, 1, line, Some(25.0), Interpolation::Bilinear),
+            vec![Some(14.5), None, Some(13.0)]
+        );
+    }
+
+    #[test]
+    fn truncated_and_mismatched_files_are_rejected() {
+        let path = temp_path("elevation_cache_truncated");
+        saved_cache(&path);
+        assert!(read_cache_file(&path, "other.tif", &Vec::new()).is_err());
+        let bytes = fs_err::read(&path).unwrap();
+        for len in [4, 20, bytes.len() - 3] {
+            fs_err::write(&path, &bytes[..len]).unwrap();
+            assert!(read_cache_file(&path, "dem.tif", &Vec::new()).is_err());
+        }
+        // a corrupt header length is rejected before allocating for it
+        let mut corrupt = bytes.clone();
+        corrupt[12..20].copy_from_slice(&u64::MAX.to_le_bytes());
+        fs_err::write(&path, &corrupt).unwrap();
+        assert!(read_cache_file(&path, "dem.tif", &Vec::new()).is_err());
+        fs_err::remove_file(&path).unwrap();
+    }
+}
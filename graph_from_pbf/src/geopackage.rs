@@ -0,0 +1,233 @@
+use crate::*;
+use crate::traversal_times::EdgeElevation;
+use std::collections::HashMap;
+
+use geo::{Coord, HaversineLength, LineString};
+use rusqlite::{params, Connection};
+
+// WGS84, the only coordinate system used by the outputs
+const SRS_ID: i32 = 4326;
+
+const FILE_NAME: &str = "graph.gpkg";
+
+// Removes the GeoPackage of an earlier run, so it doesn't keep layers of modes no longer written
+pub fn remove(output_directory: &str) -> Result<()> {
+    let path = format!("{output_directory}/{FILE_NAME}");
+    if std::path::Path::new(&path).exists() {
+        fs_err::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+// Adds the {mode}_nodes layer of points and the {mode}_links layer of linestrings oriented in the
+// direction of travel to graph.gpkg, which holds the layers of every mode for viewing in QGIS and
+// similar
+pub fn write(
+    nodes: &HashMap<usize, Coord>,
+    node_elevations: &Vec<Option<f32>>,
+    node_osm_ids: &Vec<i64>,
+    graph: &Vec<Vec<(usize, usize, u16, u16, u32)>>,
+    link_edges: &Vec<(usize, bool)>,
+    link_elevations: &Vec<EdgeElevation>,
+    edges: &Vec<Edge>,
+    output_directory: &str,
+    mode: &str,
+) -> Result<()> {
+    println!("Writing GeoPackage layers");
+    let mut connection = Connection::open(format!("{output_directory}/{FILE_NAME}"))?;
+    create_metadata_tables(&connection)?;
+    let transaction = connection.transaction()?;
+
+    let nodes_table = format!("{mode}_nodes");
+    let links_table = format!("{mode}_links");
+    transaction.execute_batch(&format!(
+        "CREATE TABLE {nodes_table} (
+            fid INTEGER PRIMARY KEY AUTOINCREMENT,
+            geom POINT,
+            node_id INTEGER NOT NULL,
+            osm_id INTEGER NOT NULL,
+            elevation REAL
+        );
+        CREATE TABLE {links_table} (
+            fid INTEGER PRIMARY KEY AUTOINCREMENT,
+            geom LINESTRING,
+            link_id INTEGER NOT NULL,
+            from_node INTEGER NOT NULL,
+            to_node INTEGER NOT NULL,
+            osm_way_id INTEGER NOT NULL,
+            traversal_time INTEGER NOT NULL,
+            length REAL NOT NULL,
+            ascent REAL NOT NULL,
+            descent REAL NOT NULL,
+            max_gradient REAL NOT NULL,
+            departure_angle INTEGER NOT NULL,
+            arrival_angle INTEGER NOT NULL
+        );"
+    ))?;
+
+    {
+        let mut insert = transaction.prepare(&format!(
+            "INSERT INTO {nodes_table} (geom, node_id, osm_id, elevation) VALUES (?1, ?2, ?3, ?4)"
+        ))?;
+        for id in 0..nodes.len() {
+            let coord = nodes[&id];
+            insert.execute(params![
+                geometry_blob(&[coord], None),
+                id,
+                node_osm_ids[id],
+                node_elevations[id],
+            ])?;
+        }
+    }
+    register_layer(&transaction, &nodes_table, "POINT", nodes.values().copied())?;
+
+    let edges_by_id: HashMap<usize, &Edge> = edges.iter().map(|edge| (edge.id, edge)).collect();
+    {
+        let mut insert = transaction.prepare(&format!(
+            "INSERT INTO {links_table} (geom, link_id, from_node, to_node, osm_way_id, traversal_time,
+                length, ascent, descent, max_gradient, departure_angle, arrival_angle)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)"
+        ))?;
+        for (from_node, links) in graph.iter().enumerate() {
+            for &(traversal_time, to_node, departure_angle, arrival_angle, link_id) in links {
+                let (edge_id, forward) = link_edges[link_id as usize];
+                let edge = edges_by_id[&edge_id];
+                let mut linestring = edge.linestring.clone();
+                if !forward {
+                    linestring.0.reverse();
+                }
+                let elevation = &link_elevations[link_id as usize];
+                insert.execute(params![
+                    geometry_blob(&linestring.0, Some(envelope(&linestring))),
+                    link_id,
+                    from_node,
+                    to_node,
+                    edge.osm_id,
+                    traversal_time,
+                    linestring.haversine_length(),
+                    elevation.ascent,
+                    elevation.descent,
+                    elevation.max_gradient,
+                    departure_angle,
+                    arrival_angle,
+                ])?;
+            }
+        }
+    }
+    register_layer(
+        &transaction,
+        &links_table,
+        "LINESTRING",
+        edges.iter().flat_map(|edge| edge.linestring.0.iter().copied()),
+    )?;
+    transaction.commit()?;
+    Ok(())
+}
+
+// The tables every GeoPackage needs, with the coordinate systems required by the specification.
+// They're created by the first mode written and kept by the rest.
+fn create_metadata_tables(connection: &Connection) -> Result<()> {
+    connection.execute_batch(
+        "PRAGMA application_id = 1196444487;
+        PRAGMA user_version = 10300;
+        CREATE TABLE IF NOT EXISTS gpkg_spatial_ref_sys (
+            srs_name TEXT NOT NULL,
+            srs_id INTEGER PRIMARY KEY,
+            organization TEXT NOT NULL,
+            organization_coordsys_id INTEGER NOT NULL,
+            definition TEXT NOT NULL,
+            description TEXT
+        );
+        CREATE TABLE IF NOT EXISTS gpkg_contents (
+            table_name TEXT NOT NULL PRIMARY KEY,
+            data_type TEXT NOT NULL,
+            identifier TEXT UNIQUE,
+            description TEXT DEFAULT '',
+            last_change DATETIME NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ','now')),
+            min_x DOUBLE,
+            min_y DOUBLE,
+            max_x DOUBLE,
+            max_y DOUBLE,
+            srs_id INTEGER,
+            CONSTRAINT fk_gc_r_srs_id FOREIGN KEY (srs_id) REFERENCES gpkg_spatial_ref_sys(srs_id)
+        );
+        CREATE TABLE IF NOT EXISTS gpkg_geometry_columns (
+            table_name TEXT NOT NULL,
+            column_name TEXT NOT NULL,
+            geometry_type_name TEXT NOT NULL,
+            srs_id INTEGER NOT NULL,
+            z TINYINT NOT NULL,
+            m TINYINT NOT NULL,
+            CONSTRAINT pk_geom_cols PRIMARY KEY (table_name, column_name),
+            CONSTRAINT fk_gc_tn FOREIGN KEY (table_name) REFERENCES gpkg_contents(table_name),
+            CONSTRAINT fk_gc_srs FOREIGN KEY (srs_id) REFERENCES gpkg_spatial_ref_sys (srs_id)
+        );
+        INSERT OR IGNORE INTO gpkg_spatial_ref_sys VALUES
+            ('Undefined cartesian SRS', -1, 'NONE', -1, 'undefined', 'undefined cartesian coordinate reference system'),
+            ('Undefined geographic SRS', 0, 'NONE', 0, 'undefined', 'undefined geographic coordinate reference system'),
+            ('WGS 84 geodetic', 4326, 'EPSG', 4326,
+             'GEOGCS[\"WGS 84\",DATUM[\"WGS_1984\",SPHEROID[\"WGS 84\",6378137,298.257223563,AUTHORITY[\"EPSG\",\"7030\"]],AUTHORITY[\"EPSG\",\"6326\"]],PRIMEM[\"Greenwich\",0,AUTHORITY[\"EPSG\",\"8901\"]],UNIT[\"degree\",0.0174532925199433,AUTHORITY[\"EPSG\",\"9122\"]],AUTHORITY[\"EPSG\",\"4326\"]]',
+             'longitude/latitude coordinates in decimal degrees on the WGS 84 spheroid');",
+    )?;
+    Ok(())
+}
+
+fn register_layer(
+    connection: &Connection,
+    table_name: &str,
+    geometry_type: &str,
+    coords: impl Iterator<Item = Coord>,
+) -> Result<()> {
+    let (min_x, min_y, max_x, max_y) = bounds(coords);
+    connection.execute(
+        "INSERT INTO gpkg_contents (table_name, data_type, identifier, min_x, min_y, max_x, max_y, srs_id)
+        VALUES (?1, 'features', ?1, ?2, ?3, ?4, ?5, ?6)",
+        params![table_name, min_x, min_y, max_x, max_y, SRS_ID],
+    )?;
+    connection.execute(
+        "INSERT INTO gpkg_geometry_columns VALUES (?1, 'geom', ?2, ?3, 0, 0)",
+        params![table_name, geometry_type, SRS_ID],
+    )?;
+    Ok(())
+}
+
+fn bounds(coords: impl Iterator<Item = Coord>) -> (f64, f64, f64, f64) {
+    coords.fold(
+        (f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+        |(min_x, min_y, max_x, max_y), coord| {
+            (min_x.min(coord.x), min_y.min(coord.y), max_x.max(coord.x), max_y.max(coord.y))
+        },
+    )
+}
+
+fn envelope(linestring: &LineString) -> (f64, f64, f64, f64) {
+    bounds(linestring.0.iter().copied())
+}
+
+// A GeoPackage geometry: the "GP" header with the SRS and an optional XY envelope, followed by
+// little-endian WKB, a point for a single coordinate and a linestring otherwise
+fn geometry_blob(coords: &[Coord], envelope: Option<(f64, f64, f64, f64)>) -> Vec<u8> {
+    let mut blob: Vec<u8> = b"GP".to_vec();
+    blob.push(0); // version 1
+    // little-endian, with the envelope indicator in bits 1-3
+    blob.push(if envelope.is_some() { 0b0000_0011 } else { 0b0000_0001 });
+    blob.extend(SRS_ID.to_le_bytes());
+    if let Some((min_x, min_y, max_x, max_y)) = envelope {
+        for value in [min_x, max_x, min_y, max_y] {
+            blob.extend(value.to_le_bytes());
+        }
+    }
+
+    blob.push(1); // little-endian WKB
+    if coords.len() == 1 {
+        blob.extend(1u32.to_le_bytes());
+    } else {
+        blob.extend(2u32.to_le_bytes());
+        blob.extend((coords.len() as u32).to_le_bytes());
+    }
+    for coord in coords {
+        blob.extend(coord.x.to_le_bytes());
+        blob.extend(coord.y.to_le_bytes());
+    }
+    blob
+}
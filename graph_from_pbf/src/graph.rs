@@ -1,23 +1,28 @@
 use crate::angles::Angles;
+use anyhow::Result;
+use fs_err::File;
 use geo::Coord;
+use geojson::{Feature, Geometry};
 use graph_from_pbf::Edge;
 
 use std::collections::HashMap;
+use std::io::{BufWriter, Write};
 
 pub fn process(
     graph_nodes_lookup: HashMap<i64, (usize, Coord)>,
     traversal_times: HashMap<usize, (usize, usize)>,
     angles: HashMap<usize, Angles>,
-    edges: Vec<Edge>,
+    edges: &Vec<Edge>,
 ) -> (
     Vec<Vec<(usize, usize, u16, u16, u32)>>,
     HashMap<usize, Coord>,
+    Vec<(usize, bool)>,
 ) {
     println!("Creating nodes");
     let nodes = convert_graph_nodes_lookup_to_nodes(&graph_nodes_lookup);
 
     println!("Creating graph");
-    let mut all_links: Vec<(usize, usize, usize, u16, u16)> = Vec::new();
+    let mut all_links: Vec<(usize, usize, usize, u16, u16, usize, bool)> = Vec::new();
     for edge in edges {
         // forward direction
         if edge.forward {
@@ -27,6 +32,8 @@ pub fn process(
                 traversal_times[&edge.id].0,
                 angles[&edge.id].forward_departure,
                 angles[&edge.id].forward_arrival,
+                edge.id,
+                true,
             ));
         }
         // backward direction
@@ -37,14 +44,16 @@ pub fn process(
                 traversal_times[&edge.id].1,
                 angles[&edge.id].backward_departure,
                 angles[&edge.id].backward_arrival,
+                edge.id,
+                false,
             ));
         }
     }
     all_links.sort();
 
-    let graph = group_links_into_graph(all_links);
+    let (graph, link_edges) = group_links_into_graph(all_links);
 
-    (graph, nodes)
+    (graph, nodes, link_edges)
 }
 
 fn convert_graph_nodes_lookup_to_nodes(
@@ -57,12 +66,25 @@ fn convert_graph_nodes_lookup_to_nodes(
     nodes
 }
 
+// The OSM node id of each graph node, indexed by graph node id
+pub fn node_osm_ids(graph_nodes_lookup: &HashMap<i64, (usize, Coord)>) -> Vec<i64> {
+    let mut osm_ids: Vec<i64> = vec![0; graph_nodes_lookup.len()];
+    for (osm_id, (id, _)) in graph_nodes_lookup {
+        osm_ids[*id] = *osm_id;
+    }
+    osm_ids
+}
+
+// Also returns the edge id and direction (true if forward) of each link, indexed by link_id
 fn group_links_into_graph(
-    all_links: Vec<(usize, usize, usize, u16, u16)>,
-) -> Vec<Vec<(usize, usize, u16, u16, u32)>> {
+    all_links: Vec<(usize, usize, usize, u16, u16, usize, bool)>,
+) -> (Vec<Vec<(usize, usize, u16, u16, u32)>>, Vec<(usize, bool)>) {
     let mut link_id: u32 = 0;
     let mut graph: Vec<Vec<(usize, usize, u16, u16, u32)>> = Vec::new();
-    for (start_node, end_node, traversal_time, departure_angle, arrival_angle) in all_links {
+    let mut link_edges: Vec<(usize, bool)> = Vec::new();
+    for (start_node, end_node, traversal_time, departure_angle, arrival_angle, edge_id, forward) in
+        all_links
+    {
         if graph.len() <= start_node {
             graph.resize(start_node + 1, Vec::new());
         }
@@ -73,7 +95,44 @@ fn group_links_into_graph(
             arrival_angle,
             link_id,
         ));
+        link_edges.push((edge_id, forward));
         link_id += 1;
     }
-    graph
+    (graph, link_edges)
+}
+
+// Writes a GeoJSON text sequence (RFC 8142) with one feature per link, in link_id order, with
+// the geometry oriented in the direction of travel
+pub fn write_link_geometry(
+    graph: &Vec<Vec<(usize, usize, u16, u16, u32)>>,
+    link_edges: &Vec<(usize, bool)>,
+    edges: &Vec<Edge>,
+    output_directory: &str,
+    mode: &str,
+) -> Result<()> {
+    println!("Writing link geometry");
+    let edges_by_id: HashMap<usize, &Edge> = edges.iter().map(|edge| (edge.id, edge)).collect();
+    let path = format!("{output_directory}/{mode}_link_geometry.geojsons");
+    let mut out = BufWriter::new(File::create(path)?);
+    for (from_node, links) in graph.iter().enumerate() {
+        for &(traversal_time, to_node, _, _, link_id) in links {
+            let (edge_id, forward) = link_edges[link_id as usize];
+            let edge = edges_by_id[&edge_id];
+            let mut linestring = edge.linestring.clone();
+            if !forward {
+                linestring.0.reverse();
+            }
+            let mut f = Feature::from(Geometry::from(&linestring));
+            f.set_property("link_id", link_id);
+            f.set_property("from_node", from_node);
+            f.set_property("to_node", to_node);
+            f.set_property("osm_way_id", edge.osm_id);
+            f.set_property("traversal_time", traversal_time);
+            out.write_all(b"\x1e")?;
+            serde_json::to_writer(&mut out, &f)?;
+            out.write_all(b"\n")?;
+        }
+    }
+    out.flush()?;
+    Ok(())
 }
@@ -0,0 +1,138 @@
+use crate::angles::Angles;
+use geo::Coord;
+use graph_from_pbf::Edge;
+
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JunctionType {
+    DeadEnd,
+    PseudoNode, // two edges meet, e.g. where a way's tags change
+    TJunction,
+    Crossroads,
+    Roundabout, // on a junction=roundabout or circular way
+    Complex,    // more than four edges, or four not forming two straight crossings
+}
+
+// Two edges at a crossroads continue each other if their bearings from the node are within this
+// many degrees of opposite
+const STRAIGHT_TOLERANCE: i32 = 45;
+
+// Classifies each graph node, indexed by node id, from the number of edges meeting there and
+// their bearings away from it. A loop edge meets its node twice.
+pub fn classify(
+    edges: &Vec<Edge>,
+    angles: &HashMap<usize, Angles>,
+    graph_nodes_lookup: &HashMap<i64, (usize, Coord)>,
+) -> Vec<JunctionType> {
+    println!("Classifying junctions");
+    let mut bearings: Vec<Vec<u16>> = vec![Vec::new(); graph_nodes_lookup.len()];
+    let mut on_roundabout = vec![false; graph_nodes_lookup.len()];
+    for edge in edges {
+        let start = graph_nodes_lookup[&edge.start_node].0;
+        let end = graph_nodes_lookup[&edge.end_node].0;
+        bearings[start].push(angles[&edge.id].forward_arrival);
+        bearings[end].push(angles[&edge.id].backward_arrival);
+        if edge.roundabout {
+            on_roundabout[start] = true;
+            on_roundabout[end] = true;
+        }
+    }
+
+    bearings
+        .into_iter()
+        .zip(on_roundabout)
+        .map(|(mut bearings, on_roundabout)| {
+            if on_roundabout {
+                return JunctionType::Roundabout;
+            }
+            match bearings.len() {
+                0 | 1 => JunctionType::DeadEnd,
+                2 => JunctionType::PseudoNode,
+                3 => JunctionType::TJunction,
+                4 => {
+                    bearings.sort();
+                    if is_straight(bearings[0], bearings[2]) && is_straight(bearings[1], bearings[3]) {
+                        JunctionType::Crossroads
+                    } else {
+                        JunctionType::Complex
+                    }
+                }
+                _ => JunctionType::Complex,
+            }
+        })
+        .collect()
+}
+
+fn is_straight(bearing1: u16, bearing2: u16) -> bool {
+    let difference = (bearing2 as i32 - bearing1 as i32).rem_euclid(360);
+    (difference - 180).abs() <= STRAIGHT_TOLERANCE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::edge;
+
+    // Edges leaving node 0 at each bearing, with the junctions they form at node 0 and at their
+    // far ends
+    fn classify_spokes(bearings: &[u16], roundabout: bool) -> Vec<JunctionType> {
+        let mut edges = Vec::new();
+        let mut angles = HashMap::new();
+        let mut graph_nodes_lookup = HashMap::new();
+        graph_nodes_lookup.insert(0, (0, Coord { x: 0.0, y: 0.0 }));
+        for (id, bearing) in bearings.iter().enumerate() {
+            let end_node = id as i64 + 1;
+            let radians = (*bearing as f64).to_radians();
+            let end = (0.001 * radians.sin(), 0.001 * radians.cos());
+            edges.push(Edge {
+                roundabout,
+                ..edge(id, 100 + id as i64, vec![0, end_node], vec![(0.0, 0.0), end])
+            });
+            angles.insert(
+                id,
+                Angles {
+                    forward_arrival: *bearing,
+                    forward_departure: *bearing,
+                    backward_arrival: (*bearing + 180) % 360,
+                    backward_departure: (*bearing + 180) % 360,
+                },
+            );
+            graph_nodes_lookup.insert(end_node, (id + 1, Coord { x: end.0, y: end.1 }));
+        }
+        classify(&edges, &angles, &graph_nodes_lookup)
+    }
+
+    #[test]
+    fn dead_end() {
+        let junctions = classify_spokes(&[90], false);
+        assert_eq!(junctions, vec![JunctionType::DeadEnd, JunctionType::DeadEnd]);
+    }
+
+    #[test]
+    fn pseudo_node() {
+        assert_eq!(classify_spokes(&[90, 270], false)[0], JunctionType::PseudoNode);
+    }
+
+    #[test]
+    fn t_junction() {
+        assert_eq!(classify_spokes(&[90, 180, 270], false)[0], JunctionType::TJunction);
+    }
+
+    #[test]
+    fn crossroads() {
+        assert_eq!(classify_spokes(&[0, 90, 180, 270], false)[0], JunctionType::Crossroads);
+        // within the tolerance of straight on
+        assert_eq!(classify_spokes(&[10, 80, 200, 250], false)[0], JunctionType::Crossroads);
+        // four ways meeting without two straight crossings
+        assert_eq!(classify_spokes(&[0, 30, 60, 180], false)[0], JunctionType::Complex);
+    }
+
+    #[test]
+    fn roundabout() {
+        let junctions = classify_spokes(&[90, 270], true);
+        assert!(junctions.iter().all(|junction| *junction == JunctionType::Roundabout));
+    }
+}
@@ -1,9 +1,12 @@
 use anyhow::Result;
+use connectivity::io::write_json_file;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
 use fs_err::File;
 use geo::{Coord, LineString};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{BufReader, BufWriter, Read, Write};
 
 #[derive(Serialize)]
 pub struct Edge {
@@ -11,18 +14,205 @@ pub struct Edge {
     pub osm_id: i64,
     pub start_node: i64,
     pub end_node: i64,
+    pub node_ids: Vec<i64>,
     pub linestring: LineString,
     pub forward: bool,
     pub backward: bool,
+    pub follows_terrain: bool, // false for bridges, tunnels and ways on a non-zero layer
+    pub layer: i32,
+    pub roundabout: bool,
+    pub incline: Option<f32>,  // gradient along the way from the incline tag, e.g. 0.05 for 5%
+    pub start_ele: Option<f32>, // metres, from the ele tag of the start node
+    pub end_ele: Option<f32>,
+    pub speed: f32,            // m/s
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct Settings {
     pub mode: String,
+    // if set, only ways with one of these highway values are used
+    #[serde(default)]
+    pub highways: Vec<String>,
     pub tag_pairs: Vec<(String, String)>,
     pub speed: f32,           // m/s
     pub ascention_speed: f32, // s/m
     pub descent_speed: f32,   // s/m
+    // ways matching the first tag pair are kept despite tag_pairs if they also match the second
+    #[serde(default)]
+    pub tag_pair_exceptions: Vec<((String, String), (String, String))>,
+    // speeds in m/s by highway value, used instead of speed where the way has no usable maxspeed
+    #[serde(default)]
+    pub highway_speeds: HashMap<String, f32>,
+    #[serde(default)]
+    pub use_maxspeed: bool, // take the speed from the way's maxspeed tag
+    #[serde(default)]
+    pub speed_factors: Vec<SpeedFactor>,
+    #[serde(default)]
+    pub max_speed: Option<f32>, // m/s, caps the speed of every way, e.g. where assistance cuts out
+    // climbs up to this gradient cost no extra time, e.g. with electric assistance
+    #[serde(default)]
+    pub assisted_gradient: Option<f32>,
+    // metres between height samples along each segment, by default only the vertices are sampled
+    #[serde(default)]
+    pub elevation_sample_spacing: Option<f32>,
+    #[serde(default)]
+    pub dem_interpolation: Interpolation,
+    // metres; within an edge, rises and falls smaller than this between its ends are treated as
+    // noise and flattened. The ends keep their sampled heights, so steps between edges remain.
+    #[serde(default)]
+    pub elevation_hysteresis: Option<f32>,
+    #[serde(default)]
+    pub missing_elevation: MissingElevation,
+    #[serde(default)]
+    pub max_gradient: Option<f32>, // edges steeper than this are excluded, e.g. 0.08 for 8%
+    // metres along each edge to measure the bearings at its ends over, clamped to the edge length;
+    // by default the bearing of the first and last segment is used
+    #[serde(default)]
+    pub bearing_distance: Option<f32>,
+    #[serde(default)]
+    pub geodesic_bearings: bool, // instead of rhumb line bearings
+    #[serde(default)]
+    pub node_delays: Vec<NodeDelay>,
+    // nodes matching all tag pairs of any entry can't be passed
+    #[serde(default)]
+    pub node_barriers: Vec<Vec<(String, String)>>,
+    #[serde(default)]
+    pub simplify: bool, // merge chains of edges through nodes where only two edges meet
+    #[serde(default)]
+    pub islands: IslandPolicy,
+    // if set, {mode}_turn_costs is written with the cost of each turn between links
+    #[serde(default)]
+    pub turn_costs: Option<TurnCosts>,
+    #[serde(default)]
+    pub outputs: Outputs,
+}
+
+impl Settings {
+    // Whether heights change the graph or are written out, so the DEM needs sampling. Other modes,
+    // e.g. car, are treated as flat.
+    pub fn uses_elevation(&self) -> bool {
+        self.ascention_speed > 0.0
+            || self.max_gradient.is_some()
+            || self.outputs.elevation
+            || self.outputs.missing_elevation
+            || self.outputs.parquet
+            || self.outputs.geopackage
+    }
+}
+
+// How heights are read from the DEM
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Interpolation {
+    #[default]
+    Nearest, // height of the containing cell
+    Bilinear,
+}
+
+// What to do with edges that have points outside the DEM
+#[derive(Deserialize, Serialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingElevation {
+    #[default]
+    AssumeFlat, // steps to or from the missing points have no height change
+    DropEdge,
+    Interpolate, // between the nearest known heights along the edge
+}
+
+// Which strongly connected components of the graph to keep
+#[derive(Deserialize, Serialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum IslandPolicy {
+    #[default]
+    KeepAll,
+    Largest,
+    MinSize(usize), // number of nodes
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct SpeedFactor {
+    pub tag_pairs: Vec<(String, String)>, // all must match the way's tags
+    pub factor: f32,                      // multiplies speed
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct NodeDelay {
+    pub tag_pairs: Vec<(String, String)>, // all must match the node's tags
+    #[serde(default)]
+    pub on_highways: Vec<String>, // if set, the node must be on a way with one of these highway values
+    pub delay: f32,                // s
+}
+
+// Penalties in seconds by kind of turn, classified by the change in bearing between links
+#[derive(Deserialize, Serialize)]
+pub struct TurnCosts {
+    #[serde(default)]
+    pub straight: usize,
+    #[serde(default)]
+    pub left: usize,
+    #[serde(default)]
+    pub right: usize, // crosses oncoming traffic in the UK
+    #[serde(default)]
+    pub u_turn: usize,
+    // degrees either side of straight ahead still counted as straight
+    #[serde(default = "default_straight_angle")]
+    pub straight_angle: u16,
+    // turns sharper than this many degrees count as U-turns, as does reversing along the same edge
+    #[serde(default = "default_u_turn_angle")]
+    pub u_turn_angle: u16,
+}
+
+fn default_straight_angle() -> u16 {
+    30
+}
+
+fn default_u_turn_angle() -> u16 {
+    150
+}
+
+// Optional outputs written alongside {mode}_nodes and {mode}_graph
+#[derive(Deserialize, Serialize, Default)]
+pub struct Outputs {
+    #[serde(default)]
+    pub unconnected_crossings: bool, // GeoJSON of ways crossing at the same layer without a shared node
+    #[serde(default)]
+    pub removed_islands: bool, // GeoJSON of edges removed by the island policy
+    #[serde(default)]
+    pub elevation: bool, // ascent, descent, max gradient and end heights per link, and height per node
+    #[serde(default)]
+    pub missing_elevation: bool, // GeoJSON of edges with points outside the DEM
+    #[serde(default)]
+    pub junction_types: bool, // dead end, T-junction, crossroads etc per node
+    #[serde(default)]
+    pub csr_graph: bool, // binary copy of {mode}_graph, see write_csr_graph
+    #[serde(default)]
+    pub link_geometry: bool, // GeoJSON text sequence with the oriented geometry of each link
+    #[serde(default)]
+    pub parquet: bool, // nodes and links as Parquet tables with named columns
+    #[serde(default)]
+    pub geopackage: bool, // nodes and links layers for GIS
+    // compression of each JSON output by name without the mode prefix, e.g. {"graph": "zstd"}
+    #[serde(default)]
+    pub compression: HashMap<String, Compression>,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip, // .json.gz
+    Zstd, // .json.zst
+}
+
+impl Compression {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -50,29 +240,267 @@ pub fn read_settings(mode: &str) -> Result<Settings> {
 }
 
 pub fn read_timetables(path: &str) -> Result<Vec<InputTimetable>> {
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
+    let reader = open_reader(path)?;
     let timetables: Vec<InputTimetable> = serde_json::from_reader(reader)?;
     Ok(timetables)
 }
 
 pub fn read_pt_stops(path: &str) -> Result<Vec<(usize, Coord)>> {
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
+    let reader = open_reader(path)?;
     let pt_stops: Vec<(usize, Coord)> = serde_json::from_reader(reader)?;
     Ok(pt_stops)
 }
 
-pub fn read_walk_nodes() -> Result<HashMap<usize, Coord>> {
-    let file = File::open("../data/walk_nodes.json")?;
-    let reader = BufReader::new(file);
+// The walk outputs in output_directory, in whichever compression they were written with
+pub fn read_walk_nodes(output_directory: &str) -> Result<HashMap<usize, Coord>> {
+    let reader = open_reader(&find_json("walk_nodes", output_directory)?)?;
     let walk_nodes: HashMap<usize, Coord> = serde_json::from_reader(reader)?;
     Ok(walk_nodes)
 }
 
-pub fn read_walk_graph() -> Result<Vec<Vec<(usize, usize, u16, u16, u32)>>> {
-    let file = File::open("../data/walk_graph.json")?;
-    let reader = BufReader::new(file);
+pub fn read_walk_graph(output_directory: &str) -> Result<Vec<Vec<(usize, usize, u16, u16, u32)>>> {
+    let reader = open_reader(&find_json("walk_graph", output_directory)?)?;
     let walk_graph: Vec<Vec<(usize, usize, u16, u16, u32)>> = serde_json::from_reader(reader)?;
     Ok(walk_graph)
 }
+
+// The path write_json used for name, trying each compression's extension in turn
+fn find_json(name: &str, output_directory: &str) -> Result<String> {
+    [Compression::None, Compression::Zstd, Compression::Gzip]
+        .into_iter()
+        .map(|compression| format!("{output_directory}/{name}.json{}", compression.extension()))
+        .find(|path| std::path::Path::new(path).exists())
+        .ok_or_else(|| anyhow::anyhow!("No {name}.json, .json.zst or .json.gz in {output_directory}"))
+}
+
+// Writes {output_directory}/{name}.json, with .gz or .zst appended if compressed
+pub fn write_json<T: Serialize>(
+    name: &str,
+    output_directory: &str,
+    data: &T,
+    compression: Compression,
+) -> Result<()> {
+    let path = format!("{output_directory}/{name}.json{}", compression.extension());
+    match compression {
+        Compression::None => write_json_file(name.to_string(), output_directory, data)?,
+        Compression::Gzip => {
+            let out = BufWriter::new(File::create(path)?);
+            let mut encoder = GzEncoder::new(out, flate2::Compression::default());
+            serde_json::to_writer(&mut encoder, data)?;
+            encoder.finish()?.flush()?;
+        }
+        Compression::Zstd => {
+            let out = BufWriter::new(File::create(path)?);
+            let mut encoder = zstd::Encoder::new(out, 0)?;
+            serde_json::to_writer(&mut encoder, data)?;
+            encoder.finish()?.flush()?;
+        }
+    }
+    Ok(())
+}
+
+// Opens a file for reading, decompressing it if the name ends in .gz or .zst
+pub fn open_reader(path: &str) -> Result<Box<dyn Read>> {
+    let file = BufReader::new(File::open(path)?);
+    Ok(if path.ends_with(".gz") {
+        Box::new(BufReader::new(MultiGzDecoder::new(file)))
+    } else if path.ends_with(".zst") {
+        Box::new(BufReader::new(zstd::Decoder::with_buffer(file)?))
+    } else {
+        Box::new(file)
+    })
+}
+
+// Binary compressed sparse row form of a graph, written as {mode}_graph.csr. All values are
+// little-endian, starting with a 32 byte header:
+//   magic b"GRAPHCSR", format version u32, reserved u32, node count u64, link count u64
+// followed by the arrays, ordered so each starts aligned to its element size when memory-mapped:
+//   offsets: (node count + 1) x u64, the links of node i are offsets[i]..offsets[i + 1]
+//   to, traversal_time, link_id: link count x u32 each
+//   departure_angle, arrival_angle: link count x u16 each
+// Links are stored in the same order as {mode}_graph, so position i holds link_id i.
+pub const CSR_MAGIC: &[u8; 8] = b"GRAPHCSR";
+pub const CSR_VERSION: u32 = 1;
+
+pub struct CsrGraph {
+    pub offsets: Vec<u64>,
+    pub to: Vec<u32>,
+    pub traversal_time: Vec<u32>,
+    pub link_id: Vec<u32>,
+    pub departure_angle: Vec<u16>,
+    pub arrival_angle: Vec<u16>,
+}
+
+impl CsrGraph {
+    pub fn node_count(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    // Positions in the link arrays of the links leaving the node
+    pub fn links(&self, node: usize) -> std::ops::Range<usize> {
+        self.offsets[node] as usize..self.offsets[node + 1] as usize
+    }
+
+    // The (traversal_time, to, departure_angle, arrival_angle, link_id) lists of {mode}_graph
+    pub fn to_adjacency_lists(&self) -> Vec<Vec<(usize, usize, u16, u16, u32)>> {
+        (0..self.node_count())
+            .map(|node| {
+                self.links(node)
+                    .map(|i| {
+                        (
+                            self.traversal_time[i] as usize,
+                            self.to[i] as usize,
+                            self.departure_angle[i],
+                            self.arrival_angle[i],
+                            self.link_id[i],
+                        )
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+// The graph only extends to the last node with outgoing links, so node_count (the number of
+// nodes) is needed to give the nodes after it, e.g. reached only by one way links, empty ranges
+pub fn write_csr_graph(
+    path: &str,
+    graph: &Vec<Vec<(usize, usize, u16, u16, u32)>>,
+    node_count: usize,
+) -> Result<()> {
+    if graph.len() > node_count {
+        anyhow::bail!("Graph has links from {} nodes but only {node_count} nodes", graph.len());
+    }
+    let link_count: usize = graph.iter().map(|links| links.len()).sum();
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(CSR_MAGIC)?;
+    out.write_all(&CSR_VERSION.to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+    out.write_all(&(node_count as u64).to_le_bytes())?;
+    out.write_all(&(link_count as u64).to_le_bytes())?;
+
+    let mut offset: u64 = 0;
+    out.write_all(&offset.to_le_bytes())?;
+    for node in 0..node_count {
+        offset += graph.get(node).map_or(0, |links| links.len()) as u64;
+        out.write_all(&offset.to_le_bytes())?;
+    }
+    let links = || graph.iter().flatten();
+    for (_, to, _, _, _) in links() {
+        out.write_all(&u32::try_from(*to)?.to_le_bytes())?;
+    }
+    for (traversal_time, _, _, _, _) in links() {
+        out.write_all(&u32::try_from(*traversal_time)?.to_le_bytes())?;
+    }
+    for (_, _, _, _, link_id) in links() {
+        out.write_all(&link_id.to_le_bytes())?;
+    }
+    for (_, _, departure_angle, _, _) in links() {
+        out.write_all(&departure_angle.to_le_bytes())?;
+    }
+    for (_, _, _, arrival_angle, _) in links() {
+        out.write_all(&arrival_angle.to_le_bytes())?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+pub fn read_csr_graph(path: &str) -> Result<CsrGraph> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut header = [0u8; 32];
+    reader.read_exact(&mut header)?;
+    if &header[0..8] != CSR_MAGIC {
+        anyhow::bail!("{path} isn't a CSR graph");
+    }
+    let version = u32::from_le_bytes(header[8..12].try_into()?);
+    if version != CSR_VERSION {
+        anyhow::bail!("{path} has CSR format version {version}, expected {CSR_VERSION}");
+    }
+    let node_count = u64::from_le_bytes(header[16..24].try_into()?) as usize;
+    let link_count = u64::from_le_bytes(header[24..32].try_into()?) as usize;
+
+    let offsets = read_le_array(&mut reader, node_count + 1, u64::from_le_bytes)?;
+    if offsets.last() != Some(&(link_count as u64)) {
+        anyhow::bail!("{path} has offsets inconsistent with its link count");
+    }
+    Ok(CsrGraph {
+        offsets,
+        to: read_le_array(&mut reader, link_count, u32::from_le_bytes)?,
+        traversal_time: read_le_array(&mut reader, link_count, u32::from_le_bytes)?,
+        link_id: read_le_array(&mut reader, link_count, u32::from_le_bytes)?,
+        departure_angle: read_le_array(&mut reader, link_count, u16::from_le_bytes)?,
+        arrival_angle: read_le_array(&mut reader, link_count, u16::from_le_bytes)?,
+    })
+}
+
+fn read_le_array<T, const N: usize>(
+    reader: &mut impl Read,
+    count: usize,
+    from_le_bytes: fn([u8; N]) -> T,
+) -> Result<Vec<T>> {
+    let mut bytes = vec![0u8; count * N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes
+        .chunks_exact(N)
+        .map(|chunk| from_le_bytes(chunk.try_into().unwrap()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csr_graph_round_trip() {
+        // node 3 only has an incoming link, so the graph stops at node 2
+        let graph: Vec<Vec<(usize, usize, u16, u16, u32)>> = vec![
+            vec![(10, 1, 90, 85, 0), (20, 2, 180, 175, 1)],
+            vec![],
+            vec![(30, 3, 270, 265, 2)],
+        ];
+        let csr = round_trip(&graph, 4);
+        assert_eq!(csr.node_count(), 4);
+        assert_eq!(csr.offsets, vec![0, 2, 2, 3, 3]);
+        assert_eq!(csr.links(3), 3..3);
+        let mut expected = graph.clone();
+        expected.push(Vec::new());
+        assert_eq!(csr.to_adjacency_lists(), expected);
+
+        // the last three nodes have no outgoing links, so the graph stops at node 1
+        let graph: Vec<Vec<(usize, usize, u16, u16, u32)>> = vec![
+            vec![(10, 2, 90, 85, 0)],
+            vec![(20, 3, 0, 355, 1), (30, 4, 180, 175, 2)],
+        ];
+        let csr = round_trip(&graph, 5);
+        assert_eq!(csr.node_count(), 5);
+        assert_eq!(csr.offsets, vec![0, 1, 3, 3, 3, 3]);
+        for node in 2..5 {
+            assert_eq!(csr.links(node), 3..3);
+        }
+        let mut expected = graph.clone();
+        expected.extend(vec![Vec::new(); 3]);
+        assert_eq!(csr.to_adjacency_lists(), expected);
+    }
+
+    #[test]
+    fn read_compressed_walk_graph() {
+        let output_directory = std::env::temp_dir().join(format!("walk_graph_{}", std::process::id()));
+        fs_err::create_dir_all(&output_directory).unwrap();
+        let output_directory = output_directory.to_str().unwrap();
+        let graph: Vec<Vec<(usize, usize, u16, u16, u32)>> =
+            vec![vec![(10, 1, 90, 85, 0)], vec![(20, 0, 270, 265, 1)]];
+        write_json("walk_graph", output_directory, &graph, Compression::Zstd).unwrap();
+        assert_eq!(read_walk_graph(output_directory).unwrap(), graph);
+        assert!(read_walk_nodes(output_directory).is_err());
+        fs_err::remove_dir_all(output_directory).unwrap();
+    }
+
+    fn round_trip(graph: &Vec<Vec<(usize, usize, u16, u16, u32)>>, node_count: usize) -> CsrGraph {
+        let path = std::env::temp_dir().join(format!("csr_round_trip_{}.csr", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_csr_graph(path, graph, node_count).unwrap();
+        let csr = read_csr_graph(path).unwrap();
+        fs_err::remove_file(path).unwrap();
+        csr
+    }
+}
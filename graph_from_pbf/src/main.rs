@@ -1,61 +1,249 @@
 mod angles;
+mod components;
+mod crossings;
+mod dem;
 mod edges;
+mod elevation_cache;
+mod geopackage;
 mod graph;
+mod junctions;
+mod manifest;
+mod maxspeed;
+mod node_delays;
+mod parquet_output;
 pub mod pt_stops;
 pub mod public_transport_graphs;
+mod report;
+mod simplify;
+#[cfg(test)]
+mod test_utils;
 mod traversal_times;
+mod turns;
 
 use anyhow::Result;
 use graph_from_pbf::{
-    read_settings, Edge, Settings,
+    read_pt_stops, read_settings, write_csr_graph, write_json, Edge, IslandPolicy, Settings,
 };
-use connectivity::io::write_json_file;
+use serde::Serialize;
+use elevation_cache::ElevationCache;
+use manifest::{Manifest, ModeManifest, Timings};
+use report::Report;
+
+// Modes with settings in settings/{mode}.json, any of which can be built with --modes
+const MODES: [&str; 6] = ["walk", "cycling", "ebike", "escooter", "wheelchair", "car"];
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 9 {
-        panic!("Call with the input path to each E/W/S osm.pbf, GeoTIFF, output directory, PT toggle, PT stops path, and PT routes path");  
+    let mut args: Vec<String> = std::env::args().collect();
+    // --modes takes a comma-separated list of the modes to build, by default walk and cycling
+    let mut modes = vec!["walk".to_string(), "cycling".to_string()];
+    if let Some(idx) = args.iter().position(|arg| arg == "--modes") {
+        let Some(list) = args.get(idx + 1) else {
+            panic!("--modes needs a comma-separated list of modes, e.g. walk,cycling,car");
+        };
+        modes = list.split(',').map(|mode| mode.to_string()).collect();
+        if let Some(mode) = modes.iter().find(|mode| !MODES.contains(&mode.as_str())) {
+            panic!("Unknown mode {mode}, expected one of {}", MODES.join(", "));
+        }
+        args.drain(idx..idx + 2);
+    }
+    if args.len() != 9 && args.len() != 10 {
+        panic!("Call with the input path to each E/W/S osm.pbf, DEM (GeoTIFF, .hgt, directory of tiles or mosaic JSON), output directory, PT toggle, PT stops path, PT routes path, and optionally an elevation cache path and --modes");  
     }
     let osm_paths: Vec<&str> = vec![&args[1], &args[2], &args[3]];
     let pt_toggle = args[6].parse::<bool>().unwrap();
     let pt_paths: Vec<&str> = vec![&args[7], &args[8]];
-    for mode in ["walk", "cycling"].iter() {
-        run(osm_paths.clone(), &args[4], &args[5], pt_toggle, mode, pt_paths.clone()).unwrap();
+    let elevation_cache_path = args.get(9).map(|path| path.as_str());
+    // hashes the inputs, including the elevation cache before it's loaded and saved again
+    let mut manifest =
+        Manifest::new(&osm_paths, &args[4], pt_toggle, &pt_paths, elevation_cache_path).unwrap();
+    // heights sampled for one mode are reused by the others
+    let mut elevation_cache = ElevationCache::new(&args[4], elevation_cache_path).unwrap();
+    geopackage::remove(&args[5]).unwrap();
+    for mode in &modes {
+        run(
+            osm_paths.clone(),
+            &mut elevation_cache,
+            &args[5],
+            pt_toggle,
+            mode,
+            pt_paths.clone(),
+            &mut manifest,
+        )
+        .unwrap();
     }
+    elevation_cache.save().unwrap();
+    manifest.write(&args[5]).unwrap();
 }
 
 fn run(
     osm_paths: Vec<&str>,
-    tif_path: &str,
+    elevation_cache: &mut ElevationCache,
     output_directory: &str,
     pt_toggle: bool,
     mode: &str,
     pt_paths: Vec<&str>,
+    manifest: &mut Manifest,
 ) -> Result<()> {
+    let mut timings = Timings::new();
     let settings = read_settings(mode)?;
+    let mut report = Report::default();
 
-    let (graph_nodes_lookup, edges) = edges::process(osm_paths, &settings)?;
-    let traversal_times = traversal_times::calculate(&edges, tif_path, &settings);
-    let angles = angles::calculate(&edges);
-    let (graph, nodes) = graph::process(graph_nodes_lookup, traversal_times, angles, edges);
+    let (mut graph_nodes_lookup, mut edges, delays) =
+        edges::process(osm_paths, &settings, &mut report)?;
+    timings.record("edges");
+    if settings.outputs.unconnected_crossings {
+        crossings::write_unconnected(&edges, output_directory, mode)?;
+    }
+    if settings.uses_elevation() {
+        elevation_cache.fill(&edges, &settings);
+    }
+    timings.record("elevation");
+    let (mut traversal_times, mut elevations, missing_elevation_edges) =
+        traversal_times::calculate(&edges, elevation_cache, &settings, &mut report);
+    timings.record("traversal_times");
+    if settings.outputs.missing_elevation {
+        traversal_times::write_missing_elevation(&edges, &missing_elevation_edges, output_directory, mode)?;
+    }
+    // edges without a traversal time, e.g. too steep for the mode or outside the DEM, are removed
+    let num_edges = edges.len();
+    edges.retain(|edge| traversal_times.contains_key(&edge.id));
+    if edges.len() < num_edges {
+        report.untraversable_edge_count = num_edges - edges.len();
+        graph_nodes_lookup = edges::renumber_graph_nodes(&graph_nodes_lookup, &edges);
+    }
+    node_delays::apply(&mut traversal_times, &edges, &delays);
+    let mut angles = angles::calculate(&edges, &settings);
+    let mut edge_origins = None;
+    if settings.simplify {
+        let origins;
+        (edges, traversal_times, angles, elevations, origins) =
+            simplify::process(edges, traversal_times, angles, elevations);
+        graph_nodes_lookup = edges::renumber_graph_nodes(&graph_nodes_lookup, &edges);
+        edge_origins = Some(origins);
+    }
+    if !matches!(settings.islands, IslandPolicy::KeepAll) {
+        let removed_edges;
+        (edges, removed_edges) =
+            components::remove_islands(edges, &graph_nodes_lookup, &settings.islands, &mut report);
+        graph_nodes_lookup = edges::renumber_graph_nodes(&graph_nodes_lookup, &edges);
+        if settings.outputs.removed_islands {
+            components::write_removed(&removed_edges, output_directory, mode)?;
+        }
+    }
+    timings.record("graph_cleanup");
+    let node_elevations =
+        traversal_times::node_elevations(elevation_cache, &settings, &graph_nodes_lookup);
+    if settings.outputs.elevation {
+        write_output(
+            format!("{mode}_node_elevation"),
+            output_directory,
+            &node_elevations,
+            &settings,
+        )?;
+    }
+    if settings.outputs.junction_types {
+        let junction_types = junctions::classify(&edges, &angles, &graph_nodes_lookup);
+        write_output(
+            format!("{mode}_junction_types"),
+            output_directory,
+            &junction_types,
+            &settings,
+        )?;
+    }
+    let node_osm_ids = graph::node_osm_ids(&graph_nodes_lookup);
+    let (graph, nodes, link_edges) =
+        graph::process(graph_nodes_lookup, traversal_times, angles, &edges);
 
-    write_json_file(format!("{mode}_nodes"), output_directory, &nodes)?;
-    write_json_file(format!("{mode}_graph"), output_directory, &graph)?;
+    write_output(format!("{mode}_nodes"), output_directory, &nodes, &settings)?;
+    write_output(format!("{mode}_node_osm_ids"), output_directory, &node_osm_ids, &settings)?;
+    write_output(format!("{mode}_graph"), output_directory, &graph, &settings)?;
+    if settings.outputs.csr_graph {
+        write_csr_graph(&format!("{output_directory}/{mode}_graph.csr"), &graph, nodes.len())?;
+    }
+    if settings.outputs.parquet {
+        parquet_output::write_nodes(&nodes, &node_elevations, &node_osm_ids, output_directory, mode)?;
+        parquet_output::write_links(&graph, &link_edges, &edges, output_directory, mode)?;
+    }
+    if settings.outputs.link_geometry {
+        graph::write_link_geometry(&graph, &link_edges, &edges, output_directory, mode)?;
+    }
+    let link_elevations = traversal_times::link_elevations(&elevations, &link_edges);
+    if settings.outputs.elevation {
+        write_output(
+            format!("{mode}_link_elevation"),
+            output_directory,
+            &link_elevations,
+            &settings,
+        )?;
+    }
+    if settings.outputs.geopackage {
+        geopackage::write(
+            &nodes,
+            &node_elevations,
+            &node_osm_ids,
+            &graph,
+            &link_edges,
+            &link_elevations,
+            &edges,
+            output_directory,
+            mode,
+        )?;
+    }
+    if let Some(turn_costs) = &settings.turn_costs {
+        let turn_costs = turns::calculate(&graph, &link_edges, turn_costs);
+        write_output(format!("{mode}_turn_costs"), output_directory, &turn_costs, &settings)?;
+    }
+    if let Some(edge_origins) = edge_origins {
+        let link_origins = simplify::link_origins(&edge_origins, &link_edges);
+        write_output(format!("{mode}_link_origins"), output_directory, &link_origins, &settings)?;
+    }
+    write_output(format!("{mode}_report"), output_directory, &report, &settings)?;
+    timings.record("outputs");
 
+    let node_count = nodes.len();
+    let link_count = link_edges.len();
+    let mut pt_stop_count = None;
     if mode == "walk" && pt_toggle {
+        let pt_stops = read_pt_stops(pt_paths[1])?;
+        pt_stop_count = Some(pt_stops.len());
         let (pt_graph_walk, pt_graph_routes, pt_graph_routes_reverse) =
-            public_transport_graphs::process(graph, nodes, pt_paths)?;
-        write_json_file(format!("pt_graph_walk"), output_directory, &pt_graph_walk)?;
-        write_json_file(
+            public_transport_graphs::process(graph, nodes, &pt_stops, pt_paths[0])?;
+        write_output(format!("pt_graph_walk"), output_directory, &pt_graph_walk, &settings)?;
+        write_output(
             format!("pt_graph_routes"),
             output_directory,
             &pt_graph_routes,
+            &settings,
         )?;
-        write_json_file(
+        write_output(
             format!("pt_graph_routes_reverse"),
             output_directory,
             &pt_graph_routes_reverse,
+            &settings,
         )?;
+        timings.record("public_transport");
     }
+
+    manifest.modes.push(ModeManifest {
+        mode: mode.to_string(),
+        settings,
+        node_count,
+        link_count,
+        pt_stop_count,
+        timings: timings.stages,
+    });
     Ok(())
 }
+
+// Writes a JSON output, compressed as set in settings.outputs.compression for its name without the
+// mode prefix, e.g. "graph" for {mode}_graph
+fn write_output<T: Serialize>(
+    name: String,
+    output_directory: &str,
+    data: &T,
+    settings: &Settings,
+) -> Result<()> {
+    let key = name.strip_prefix(&format!("{}_", settings.mode)).unwrap_or(&name);
+    let compression = settings.outputs.compression.get(key).copied().unwrap_or_default();
+    write_json(&name, output_directory, data, compression)
+}
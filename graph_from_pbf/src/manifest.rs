@@ -0,0 +1,263 @@
+use crate::*;
+use crate::dem::Dem;
+use graph_from_pbf::{write_json, Compression, Interpolation};
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use flate2::read::ZlibDecoder;
+use fs_err::File;
+use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+// Provenance of the outputs: the inputs and code version used, and the settings, counts and
+// timings of each mode. Written as manifest.json once every mode has finished.
+#[derive(Serialize)]
+pub struct Manifest {
+    pub crate_version: &'static str,
+    pub git_commit: &'static str,
+    pub git_dirty: &'static str, // true, false or unknown
+    pub started_at: u64, // unix seconds
+    pub inputs: Vec<InputFile>,
+    pub modes: Vec<ModeManifest>,
+}
+
+#[derive(Serialize)]
+pub struct InputFile {
+    pub role: &'static str, // osm, dem, pt_timetables, pt_stops or elevation_cache
+    pub path: String,
+    pub size: u64, // bytes
+    pub sha256: String,
+    pub osm_header: Option<OsmHeader>,
+}
+
+// From the OSMHeader block at the start of a PBF
+#[derive(Serialize, Default)]
+pub struct OsmHeader {
+    pub writing_program: Option<String>,
+    pub source: Option<String>,
+    pub replication_timestamp: Option<i64>, // unix seconds
+    pub replication_sequence_number: Option<i64>,
+    pub replication_base_url: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ModeManifest {
+    pub mode: String,
+    pub settings: Settings,
+    pub node_count: usize,
+    pub link_count: usize,
+    pub pt_stop_count: Option<usize>,
+    pub timings: Vec<(String, f32)>, // seconds per stage
+}
+
+// Seconds taken by each stage of a run
+pub struct Timings {
+    stage_start: Instant,
+    pub stages: Vec<(String, f32)>,
+}
+
+impl Timings {
+    pub fn new() -> Timings {
+        Timings {
+            stage_start: Instant::now(),
+            stages: Vec::new(),
+        }
+    }
+
+    // Records the time since the previous stage ended
+    pub fn record(&mut self, stage: &str) {
+        let now = Instant::now();
+        self.stages
+            .push((stage.to_string(), (now - self.stage_start).as_secs_f32()));
+        self.stage_start = now;
+    }
+}
+
+impl Manifest {
+    pub fn new(
+        osm_paths: &Vec<&str>,
+        dem_path: &str,
+        pt_toggle: bool,
+        pt_paths: &Vec<&str>,
+        elevation_cache_path: Option<&str>,
+    ) -> Result<Manifest> {
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut paths: Vec<(&'static str, &str)> = osm_paths.iter().map(|path| ("osm", *path)).collect();
+        // a mosaic definition is recorded along with each raster it resolves to
+        if dem_path.ends_with(".json") {
+            paths.push(("dem", dem_path));
+        }
+        let dem = Dem::open(dem_path, Interpolation::Nearest)?;
+        paths.extend(dem.source_paths().into_iter().map(|path| ("dem", path)));
+        if pt_toggle {
+            paths.push(("pt_timetables", pt_paths[0]));
+            paths.push(("pt_stops", pt_paths[1]));
+        }
+        // heights loaded from a cache are used instead of the DEM, so its contents matter too
+        if let Some(path) = elevation_cache_path.filter(|path| Path::new(path).exists()) {
+            paths.push(("elevation_cache", path));
+        }
+
+        // the inputs can be several GB, so they're hashed in parallel
+        println!("Hashing input files");
+        let progress = ProgressBar::new(paths.len() as u64).with_style(ProgressStyle::with_template(
+            "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {human_pos}/{human_len} ({per_sec}, {eta})").unwrap());
+        let inputs = paths
+            .into_par_iter()
+            .progress_with(progress)
+            .map(|(role, path)| {
+                let mut input = InputFile::new(role, path)?;
+                if role == "osm" {
+                    input.osm_header = Some(read_osm_header(path)?);
+                }
+                Ok(input)
+            })
+            .collect::<Result<Vec<InputFile>>>()?;
+        Ok(Manifest {
+            crate_version: env!("CARGO_PKG_VERSION"),
+            // set by build.rs
+            git_commit: option_env!("GIT_COMMIT").unwrap_or("unknown"),
+            git_dirty: option_env!("GIT_DIRTY").unwrap_or("unknown"),
+            started_at,
+            inputs,
+            modes: Vec::new(),
+        })
+    }
+
+    pub fn write(&self, output_directory: &str) -> Result<()> {
+        write_json("manifest", output_directory, self, Compression::None)
+    }
+}
+
+impl InputFile {
+    fn new(role: &'static str, path: &str) -> Result<InputFile> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut hasher = Sha256::new();
+        let size = std::io::copy(&mut reader, &mut hasher)?;
+        Ok(InputFile {
+            role,
+            path: Path::new(path)
+                .canonicalize()
+                .map(|path| path.to_string_lossy().to_string())
+                .unwrap_or(path.to_string()),
+            size,
+            sha256: format!("{:x}", hasher.finalize()),
+            osm_header: None,
+        })
+    }
+}
+
+// A PBF starts with a BlobHeader (prefixed by its length as a big-endian u32) describing a Blob
+// holding the HeaderBlock, raw or zlib compressed. Only the fields needed here are decoded.
+fn read_osm_header(path: &str) -> Result<OsmHeader> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut length = [0u8; 4];
+    reader.read_exact(&mut length)?;
+    let mut blob_header = vec![0u8; u32::from_be_bytes(length) as usize];
+    reader.read_exact(&mut blob_header)?;
+    let mut blob_type = String::new();
+    let mut blob_size: usize = 0;
+    for (field, value) in protobuf_fields(&blob_header)? {
+        match (field, value) {
+            (1, Value::Bytes(bytes)) => blob_type = String::from_utf8_lossy(bytes).to_string(),
+            (3, Value::Varint(size)) => blob_size = size as usize,
+            _ => {}
+        }
+    }
+    if blob_type != "OSMHeader" {
+        anyhow::bail!("{path} doesn't start with an OSMHeader block");
+    }
+
+    let mut blob = vec![0u8; blob_size];
+    reader.read_exact(&mut blob)?;
+    let mut header_block: Vec<u8> = Vec::new();
+    for (field, value) in protobuf_fields(&blob)? {
+        match (field, value) {
+            (1, Value::Bytes(raw)) => header_block = raw.to_vec(),
+            (3, Value::Bytes(zlib_data)) => {
+                ZlibDecoder::new(zlib_data).read_to_end(&mut header_block)?;
+            }
+            _ => {}
+        }
+    }
+
+    let mut header = OsmHeader::default();
+    for (field, value) in protobuf_fields(&header_block)? {
+        match (field, value) {
+            (16, Value::Bytes(bytes)) => {
+                header.writing_program = Some(String::from_utf8_lossy(bytes).to_string())
+            }
+            (17, Value::Bytes(bytes)) => header.source = Some(String::from_utf8_lossy(bytes).to_string()),
+            (32, Value::Varint(timestamp)) => header.replication_timestamp = Some(timestamp as i64),
+            (33, Value::Varint(sequence)) => {
+                header.replication_sequence_number = Some(sequence as i64)
+            }
+            (34, Value::Bytes(bytes)) => {
+                header.replication_base_url = Some(String::from_utf8_lossy(bytes).to_string())
+            }
+            _ => {}
+        }
+    }
+    Ok(header)
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed, // 32 or 64 bit values, not needed here
+}
+
+// The field numbers and values of a protobuf message, in order
+fn protobuf_fields(buffer: &[u8]) -> Result<Vec<(u64, Value<'_>)>> {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while pos < buffer.len() {
+        let key = read_varint(buffer, &mut pos)?;
+        let value = match key & 7 {
+            0 => Value::Varint(read_varint(buffer, &mut pos)?),
+            1 => {
+                pos += 8;
+                Value::Fixed
+            }
+            2 => {
+                let length = read_varint(buffer, &mut pos)? as usize;
+                let end = pos + length;
+                if end > buffer.len() {
+                    anyhow::bail!("Truncated protobuf field");
+                }
+                let bytes = &buffer[pos..end];
+                pos = end;
+                Value::Bytes(bytes)
+            }
+            5 => {
+                pos += 4;
+                Value::Fixed
+            }
+            wire_type => anyhow::bail!("Unsupported protobuf wire type {wire_type}"),
+        };
+        fields.push((key >> 3, value));
+    }
+    Ok(fields)
+}
+
+fn read_varint(buffer: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut value: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let Some(byte) = buffer.get(*pos) else {
+            anyhow::bail!("Truncated protobuf varint");
+        };
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+        if shift >= 64 {
+            anyhow::bail!("Protobuf varint too long");
+        }
+    }
+}
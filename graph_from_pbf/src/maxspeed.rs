@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+
+// Speed limits implied by national limit and zone values of maxspeed, in mph. GB values are also
+// found with the older UK: prefix.
+const GB_LIMITS: [(&str, f32); 3] = [("nsl_single", 60.0), ("nsl_dual", 70.0), ("motorway", 70.0)];
+
+const MPH: f32 = 0.44704; // m/s
+const KMH: f32 = 1.0 / 3.6; // m/s
+const KNOTS: f32 = 0.514444; // m/s
+
+// Finds the speed limit of a way in m/s, from maxspeed or, where that's missing, the tags
+// recording the type of limit (e.g. maxspeed:type=GB:nsl_single). Returns None for values without
+// a fixed speed such as "none", "signals" or "variable", leaving the highway class default to apply.
+pub fn way_maxspeed(tags: &HashMap<String, String>) -> Option<f32> {
+    ["maxspeed", "maxspeed:type", "zone:maxspeed", "source:maxspeed"]
+        .iter()
+        .find_map(|key| tags.get(*key).and_then(|value| parse(value)))
+}
+
+// Parses values like "30 mph", "50", "50 km/h", "GB:nsl_single" or "GB:zone20" into m/s
+pub fn parse(maxspeed: &str) -> Option<f32> {
+    // for multiple values, e.g. per lane, use the first
+    let maxspeed = maxspeed.split(';').next()?.trim();
+
+    if let Some((country, limit)) = maxspeed.split_once(':') {
+        let imperial = country == "GB" || country == "UK";
+        if imperial {
+            if let Some((_, mph)) = GB_LIMITS.iter().find(|(name, _)| *name == limit) {
+                return Some(mph * MPH);
+            }
+        }
+        // zones like GB:zone20 or DE:zone30, in the country's units
+        if let Some(zone) = limit.strip_prefix("zone") {
+            let speed: f32 = zone.trim().parse().ok()?;
+            return Some(speed * if imperial { MPH } else { KMH });
+        }
+        if limit == "walk" {
+            return Some(5.0 * KMH);
+        }
+        // other types, e.g. DE:urban, depend on the highway class
+        return None;
+    }
+    if maxspeed == "walk" {
+        return Some(5.0 * KMH);
+    }
+
+    let (number, unit) = match maxspeed.find(|c: char| !(c.is_ascii_digit() || c == '.')) {
+        Some(idx) => (&maxspeed[..idx], maxspeed[idx..].trim()),
+        None => (maxspeed, ""),
+    };
+    let speed: f32 = number.parse().ok()?;
+    let unit = match unit {
+        "mph" => MPH,
+        "" | "km/h" | "kmh" | "kph" => KMH,
+        "knots" => KNOTS,
+        _ => return None,
+    };
+    Some(speed * unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_values() {
+        let cases: [(&str, Option<f32>); 16] = [
+            ("30 mph", Some(30.0 * MPH)),
+            ("30mph", Some(30.0 * MPH)),
+            ("50", Some(50.0 * KMH)),
+            ("50 km/h", Some(50.0 * KMH)),
+            ("50 kph", Some(50.0 * KMH)),
+            ("10 knots", Some(10.0 * KNOTS)),
+            ("GB:nsl_single", Some(60.0 * MPH)),
+            ("GB:nsl_dual", Some(70.0 * MPH)),
+            ("UK:nsl_single", Some(60.0 * MPH)),
+            ("GB:motorway", Some(70.0 * MPH)),
+            ("GB:zone20", Some(20.0 * MPH)),
+            ("DE:zone30", Some(30.0 * KMH)),
+            ("walk", Some(5.0 * KMH)),
+            ("DE:walk", Some(5.0 * KMH)),
+            ("none", None),
+            ("20 mph;30 mph", Some(20.0 * MPH)),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parse(value), expected, "{value}");
+        }
+        for value in ["signals", "variable", "DE:urban", "30 furlongs", ""] {
+            assert_eq!(parse(value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_type_of_limit() {
+        let tags = HashMap::from([
+            ("maxspeed".to_string(), "none".to_string()),
+            ("maxspeed:type".to_string(), "GB:nsl_single".to_string()),
+        ]);
+        assert_eq!(way_maxspeed(&tags), Some(60.0 * MPH));
+        assert_eq!(way_maxspeed(&HashMap::new()), None);
+    }
+}
@@ -0,0 +1,49 @@
+use graph_from_pbf::Edge;
+
+use std::collections::HashMap;
+
+pub fn apply(
+    traversal_times: &mut HashMap<usize, (usize, usize)>,
+    edges: &Vec<Edge>,
+    node_delays: &HashMap<i64, f32>,
+) {
+    println!("Adding node delays to traversal times");
+    if node_delays.is_empty() {
+        return;
+    }
+    for edge in edges {
+        let (forward_delay, backward_delay) = edge_delays(edge, node_delays);
+        if let Some((forward, backward)) = traversal_times.get_mut(&edge.id) {
+            *forward += forward_delay.round() as usize;
+            *backward += backward_delay.round() as usize;
+        }
+    }
+}
+
+// A link is delayed by every node it passes through and by the node it arrives at. The node it
+// departs from is left out, so a junction is only counted once when moving between links.
+fn edge_delays(edge: &Edge, node_delays: &HashMap<i64, f32>) -> (f32, f32) {
+    let delay = |node: &i64| node_delays.get(node).copied().unwrap_or(0.0);
+    let num_nodes = edge.node_ids.len();
+    let interior: f32 = edge.node_ids[1..num_nodes - 1].iter().map(delay).sum();
+    (
+        interior + delay(&edge.end_node),
+        interior + delay(&edge.start_node),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::edge;
+
+    #[test]
+    fn delays_of_passed_and_arrival_nodes() {
+        let edges = vec![edge(0, 100, vec![1, 2, 3], vec![(0.0, 0.0), (0.001, 0.0), (0.002, 0.0)])];
+        let node_delays = HashMap::from([(1, 10.0), (2, 5.0), (3, 20.4)]);
+        let mut traversal_times = HashMap::from([(0, (30, 40))]);
+        apply(&mut traversal_times, &edges, &node_delays);
+        // forward skips node 1 it departs from, backward skips node 3
+        assert_eq!(traversal_times[&0], (30 + 25, 40 + 15));
+    }
+}
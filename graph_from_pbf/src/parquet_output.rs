@@ -0,0 +1,113 @@
+use crate::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use arrow_array::{
+    ArrayRef, BooleanArray, Float32Array, Float64Array, Int64Array, RecordBatch, UInt16Array,
+    UInt32Array, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema};
+use fs_err::File;
+use geo::{Coord, HaversineLength};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::metadata::KeyValue;
+use parquet::file::properties::WriterProperties;
+
+// Both files record the mode under the "mode" key of their metadata.
+
+// Writes {mode}_nodes.parquet, one row per graph node in node id order:
+//   id u64, lon f64, lat f64, elevation f32 (null outside the DEM), osm_id i64
+pub fn write_nodes(
+    nodes: &HashMap<usize, Coord>,
+    node_elevations: &Vec<Option<f32>>,
+    node_osm_ids: &Vec<i64>,
+    output_directory: &str,
+    mode: &str,
+) -> Result<()> {
+    let coords: Vec<Coord> = (0..nodes.len()).map(|id| nodes[&id]).collect();
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("id", DataType::UInt64, false),
+        Field::new("lon", DataType::Float64, false),
+        Field::new("lat", DataType::Float64, false),
+        Field::new("elevation", DataType::Float32, true),
+        Field::new("osm_id", DataType::Int64, false),
+    ]));
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(UInt64Array::from_iter_values(0..nodes.len() as u64)),
+        Arc::new(Float64Array::from_iter_values(coords.iter().map(|coord| coord.x))),
+        Arc::new(Float64Array::from_iter_values(coords.iter().map(|coord| coord.y))),
+        Arc::new(Float32Array::from(node_elevations.clone())),
+        Arc::new(Int64Array::from(node_osm_ids.clone())),
+    ];
+    write(&format!("{output_directory}/{mode}_nodes.parquet"), schema, columns, mode)
+}
+
+// Writes {mode}_links.parquet, one row per link in link_id order:
+//   link_id u32, from u64, to u64, traversal_time u32 (s), departure_angle u16 and
+//   arrival_angle u16 (degrees from north at the end and start of the link), length f32 (m),
+//   osm_way_id i64, and the flags oneway (the link can't be travelled in reverse),
+//   roundabout and follows_terrain (false on bridges and tunnels)
+pub fn write_links(
+    graph: &Vec<Vec<(usize, usize, u16, u16, u32)>>,
+    link_edges: &Vec<(usize, bool)>,
+    edges: &Vec<Edge>,
+    output_directory: &str,
+    mode: &str,
+) -> Result<()> {
+    let edges_by_id: HashMap<usize, &Edge> = edges.iter().map(|edge| (edge.id, edge)).collect();
+    let links: Vec<(usize, &(usize, usize, u16, u16, u32), &Edge)> = graph
+        .iter()
+        .enumerate()
+        .flat_map(|(from_node, links)| links.iter().map(move |link| (from_node, link)))
+        .map(|(from_node, link)| (from_node, link, edges_by_id[&link_edges[link.4 as usize].0]))
+        .collect();
+
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("link_id", DataType::UInt32, false),
+        Field::new("from", DataType::UInt64, false),
+        Field::new("to", DataType::UInt64, false),
+        Field::new("traversal_time", DataType::UInt32, false),
+        Field::new("departure_angle", DataType::UInt16, false),
+        Field::new("arrival_angle", DataType::UInt16, false),
+        Field::new("length", DataType::Float32, false),
+        Field::new("osm_way_id", DataType::Int64, false),
+        Field::new("oneway", DataType::Boolean, false),
+        Field::new("roundabout", DataType::Boolean, false),
+        Field::new("follows_terrain", DataType::Boolean, false),
+    ]));
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(UInt32Array::from_iter_values(links.iter().map(|(_, link, _)| link.4))),
+        Arc::new(UInt64Array::from_iter_values(links.iter().map(|(from, _, _)| *from as u64))),
+        Arc::new(UInt64Array::from_iter_values(links.iter().map(|(_, link, _)| link.1 as u64))),
+        Arc::new(UInt32Array::from_iter_values(links.iter().map(|(_, link, _)| link.0 as u32))),
+        Arc::new(UInt16Array::from_iter_values(links.iter().map(|(_, link, _)| link.2))),
+        Arc::new(UInt16Array::from_iter_values(links.iter().map(|(_, link, _)| link.3))),
+        Arc::new(Float32Array::from_iter_values(
+            links.iter().map(|(_, _, edge)| edge.linestring.haversine_length() as f32),
+        )),
+        Arc::new(Int64Array::from_iter_values(links.iter().map(|(_, _, edge)| edge.osm_id))),
+        Arc::new(BooleanArray::from_iter(
+            links.iter().map(|(_, _, edge)| Some(!(edge.forward && edge.backward))),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            links.iter().map(|(_, _, edge)| Some(edge.roundabout)),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            links.iter().map(|(_, _, edge)| Some(edge.follows_terrain)),
+        )),
+    ];
+    write(&format!("{output_directory}/{mode}_links.parquet"), schema, columns, mode)
+}
+
+fn write(path: &str, schema: Arc<Schema>, columns: Vec<ArrayRef>, mode: &str) -> Result<()> {
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_key_value_metadata(Some(vec![KeyValue::new("mode".to_string(), mode.to_string())]))
+        .build();
+    let mut writer = ArrowWriter::try_new(File::create(path)?, schema, Some(properties))?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
@@ -1,7 +1,7 @@
 use crate::pt_stops::add_stops;
 use anyhow::Result;
 use geo::Coord;
-use graph_from_pbf::{read_pt_stops, read_timetables, Timetable};
+use graph_from_pbf::{read_timetables, Timetable};
 use serde::Serialize;
 use std::collections::HashMap;
 
@@ -26,7 +26,8 @@ pub struct ReverseNodeRoute {
 pub fn process(
     walk_graph: Vec<Vec<(usize, usize, u16, u16, u32)>>,
     walk_nodes: HashMap<usize, Coord>,
-    pt_paths: Vec<&str>,
+    pt_stops: &Vec<(usize, Coord)>,
+    timetables_path: &str,
 ) -> Result<(Vec<NodeWalk>, Vec<NodeRoute>, Vec<ReverseNodeRoute>)> {
     println!("Creating public transport graphs");
     let walk_graph_length = walk_graph.len();
@@ -45,15 +46,14 @@ pub fn process(
         })
         .collect();
 
-    let pt_stops = read_pt_stops(pt_paths[1])?;
     add_stops(
-        &pt_stops,
+        pt_stops,
         walk_nodes,
         &mut pt_graph_walk,
         &walk_graph_length,
     );
 
-    let timetables = read_timetables(pt_paths[0])?;
+    let timetables = read_timetables(timetables_path)?;
 
     println!("Creating public transport routes graph");
     // create pt graph routes and pad with empty NodeRoutes for non-pt route nodes
@@ -0,0 +1,37 @@
+use serde::Serialize;
+
+// Summary of data problems worked around during a run, written as {mode}_report
+#[derive(Serialize, Default)]
+pub struct Report {
+    pub missing_nodes: MissingNodes,
+    pub untraversable_edge_count: usize, // e.g. steeper than the mode's max_gradient
+    pub missing_elevation: MissingElevation,
+    pub islands: Islands,
+}
+
+// Ways referencing nodes outside the extracts, typically at the extract borders
+#[derive(Serialize, Default)]
+pub struct MissingNodes {
+    pub truncated_way_count: usize,
+    pub dropped_way_count: usize,
+    pub truncated_way_ids: Vec<i64>, // kept as the runs of nodes that are present
+    pub dropped_way_ids: Vec<i64>,   // fewer than two consecutive nodes present
+}
+
+// Points along the edges without a height from the DEM, handled by the missing_elevation setting
+#[derive(Serialize, Default)]
+pub struct MissingElevation {
+    pub point_count: usize,
+    pub edge_count: usize,
+    pub dropped_edge_count: usize,
+}
+
+// Strongly connected components, and what was removed by the island policy
+#[derive(Serialize, Default)]
+pub struct Islands {
+    pub component_count: usize,
+    pub largest_component_size: usize,
+    pub kept_component_count: usize,
+    pub removed_node_count: usize,
+    pub removed_edge_count: usize,
+}
@@ -0,0 +1,419 @@
+use crate::angles::Angles;
+use crate::traversal_times::EdgeElevation;
+use graph_from_pbf::Edge;
+
+use geo::{Coord, LineString};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+
+// The original edges and OSM ways making up a link, in the direction of travel
+#[derive(Serialize)]
+pub struct LinkOrigin {
+    pub original_edge_ids: Vec<usize>,
+    pub osm_way_ids: Vec<i64>,
+}
+
+// An original edge within a chain, reversed if it's traversed from its end node to its start node
+struct Part {
+    edge_idx: usize,
+    reversed: bool,
+}
+
+// Merges chains of edges meeting at nodes with only two edges (e.g. a road split where its name
+// changes) into single edges. Returns the merged edges, their traversal times, angles and
+// elevations, and for each merged edge the ids of the original edges it was built from.
+pub fn process(
+    edges: Vec<Edge>,
+    traversal_times: HashMap<usize, (usize, usize)>,
+    angles: HashMap<usize, Angles>,
+    elevations: HashMap<usize, EdgeElevation>,
+) -> (
+    Vec<Edge>,
+    HashMap<usize, (usize, usize)>,
+    HashMap<usize, Angles>,
+    HashMap<usize, EdgeElevation>,
+    Vec<LinkOrigin>,
+) {
+    println!("Simplifying graph by contracting degree-two nodes");
+    let mut incident_edges: HashMap<i64, Vec<usize>> = HashMap::new();
+    for (idx, edge) in edges.iter().enumerate() {
+        incident_edges.entry(edge.start_node).or_insert_with(Vec::new).push(idx);
+        incident_edges.entry(edge.end_node).or_insert_with(Vec::new).push(idx);
+    }
+
+    let mut visited = vec![false; edges.len()];
+    let mut chains: Vec<VecDeque<Part>> = Vec::new();
+    for idx in 0..edges.len() {
+        if visited[idx] {
+            continue;
+        }
+        visited[idx] = true;
+        let mut chain = VecDeque::from([Part {
+            edge_idx: idx,
+            reversed: false,
+        }]);
+        // extend from the end of the chain
+        loop {
+            let node = part_end_node(&edges, chain.back().unwrap());
+            let Some(next_idx) = next_edge(&edges, &incident_edges, node, chain.back().unwrap())
+            else {
+                break;
+            };
+            if visited[next_idx] {
+                break;
+            }
+            visited[next_idx] = true;
+            chain.push_back(Part {
+                edge_idx: next_idx,
+                reversed: edges[next_idx].start_node != node,
+            });
+        }
+        // extend from the start of the chain
+        loop {
+            let node = part_start_node(&edges, chain.front().unwrap());
+            let Some(prev_idx) = next_edge(&edges, &incident_edges, node, chain.front().unwrap())
+            else {
+                break;
+            };
+            if visited[prev_idx] {
+                break;
+            }
+            visited[prev_idx] = true;
+            chain.push_front(Part {
+                edge_idx: prev_idx,
+                reversed: edges[prev_idx].end_node != node,
+            });
+        }
+        chains.push(chain);
+    }
+    println!("Merged {} edges into {}", edges.len(), chains.len());
+
+    let mut merged_edges: Vec<Edge> = Vec::new();
+    let mut merged_traversal_times: HashMap<usize, (usize, usize)> = HashMap::new();
+    let mut merged_angles: HashMap<usize, Angles> = HashMap::new();
+    let mut merged_elevations: HashMap<usize, EdgeElevation> = HashMap::new();
+    let mut edge_origins: Vec<LinkOrigin> = Vec::new();
+    for (id, chain) in chains.into_iter().enumerate() {
+        let (forward_time, backward_time) = chain
+            .iter()
+            .map(|part| oriented_traversal_time(&traversal_times, &edges[part.edge_idx], part))
+            .fold((0, 0), |(f, b), (part_f, part_b)| (f + part_f, b + part_b));
+        merged_traversal_times.insert(id, (forward_time, backward_time));
+
+        let first = oriented_angles(&angles[&edges[chain[0].edge_idx].id], &chain[0]);
+        let last_part = chain.back().unwrap();
+        let last = oriented_angles(&angles[&edges[last_part.edge_idx].id], last_part);
+        merged_angles.insert(
+            id,
+            Angles {
+                forward_arrival: first.forward_arrival,
+                forward_departure: last.forward_departure,
+                backward_arrival: last.backward_arrival,
+                backward_departure: first.backward_departure,
+            },
+        );
+
+        let part_elevations: Vec<EdgeElevation> = chain
+            .iter()
+            .map(|part| {
+                let elevation = &elevations[&edges[part.edge_idx].id];
+                if part.reversed {
+                    elevation.reversed()
+                } else {
+                    elevation.clone()
+                }
+            })
+            .collect();
+        merged_elevations.insert(
+            id,
+            EdgeElevation {
+                ascent: part_elevations.iter().map(|e| e.ascent).sum(),
+                descent: part_elevations.iter().map(|e| e.descent).sum(),
+                max_gradient: part_elevations
+                    .iter()
+                    .map(|e| e.max_gradient)
+                    .fold(0.0, f32::max),
+                start_height: part_elevations[0].start_height,
+                end_height: part_elevations.last().unwrap().end_height,
+            },
+        );
+
+        edge_origins.push(LinkOrigin {
+            original_edge_ids: chain.iter().map(|part| edges[part.edge_idx].id).collect(),
+            osm_way_ids: chain.iter().map(|part| edges[part.edge_idx].osm_id).collect(),
+        });
+        merged_edges.push(merge_chain(id, &edges, &chain));
+    }
+    (
+        merged_edges,
+        merged_traversal_times,
+        merged_angles,
+        merged_elevations,
+        edge_origins,
+    )
+}
+
+// Maps each link of the simplified graph to the original edges it was built from
+pub fn link_origins(edge_origins: &Vec<LinkOrigin>, link_edges: &Vec<(usize, bool)>) -> Vec<LinkOrigin> {
+    link_edges
+        .iter()
+        .map(|(edge_id, forward)| {
+            let mut original_edge_ids = edge_origins[*edge_id].original_edge_ids.clone();
+            let mut osm_way_ids = edge_origins[*edge_id].osm_way_ids.clone();
+            if !forward {
+                original_edge_ids.reverse();
+                osm_way_ids.reverse();
+            }
+            LinkOrigin {
+                original_edge_ids,
+                osm_way_ids,
+            }
+        })
+        .collect()
+}
+
+// The other edge at a node that can be contracted, i.e. one where exactly two edges meet and
+// that can be travelled through in the same directions as along the current edge
+fn next_edge(
+    edges: &Vec<Edge>,
+    incident_edges: &HashMap<i64, Vec<usize>>,
+    node: i64,
+    current: &Part,
+) -> Option<usize> {
+    let incident = &incident_edges[&node];
+    // a loop edge also appears twice here
+    if incident.len() != 2 || incident[0] == incident[1] {
+        return None;
+    }
+    let other_idx = if incident[0] == current.edge_idx {
+        incident[1]
+    } else {
+        incident[0]
+    };
+    // access along the current edge towards the node, and along the other edge away from it
+    let current_edge = &edges[current.edge_idx];
+    let into_node = if current_edge.end_node == node {
+        (current_edge.forward, current_edge.backward)
+    } else {
+        (current_edge.backward, current_edge.forward)
+    };
+    let other_edge = &edges[other_idx];
+    // keeps roundabouts separate from the roads joining them
+    if other_edge.roundabout != current_edge.roundabout {
+        return None;
+    }
+    let out_of_node = if other_edge.start_node == node {
+        (other_edge.forward, other_edge.backward)
+    } else {
+        (other_edge.backward, other_edge.forward)
+    };
+    if into_node != out_of_node {
+        return None;
+    }
+    Some(other_idx)
+}
+
+fn part_start_node(edges: &Vec<Edge>, part: &Part) -> i64 {
+    let edge = &edges[part.edge_idx];
+    if part.reversed {
+        edge.end_node
+    } else {
+        edge.start_node
+    }
+}
+
+fn part_end_node(edges: &Vec<Edge>, part: &Part) -> i64 {
+    let edge = &edges[part.edge_idx];
+    if part.reversed {
+        edge.start_node
+    } else {
+        edge.end_node
+    }
+}
+
+fn part_start_ele(edges: &Vec<Edge>, part: &Part) -> Option<f32> {
+    let edge = &edges[part.edge_idx];
+    if part.reversed {
+        edge.end_ele
+    } else {
+        edge.start_ele
+    }
+}
+
+fn part_end_ele(edges: &Vec<Edge>, part: &Part) -> Option<f32> {
+    let edge = &edges[part.edge_idx];
+    if part.reversed {
+        edge.start_ele
+    } else {
+        edge.end_ele
+    }
+}
+
+fn oriented_traversal_time(
+    traversal_times: &HashMap<usize, (usize, usize)>,
+    edge: &Edge,
+    part: &Part,
+) -> (usize, usize) {
+    let (forward, backward) = traversal_times[&edge.id];
+    if part.reversed {
+        (backward, forward)
+    } else {
+        (forward, backward)
+    }
+}
+
+fn oriented_angles(angles: &Angles, part: &Part) -> Angles {
+    if part.reversed {
+        Angles {
+            forward_arrival: angles.backward_arrival,
+            forward_departure: angles.backward_departure,
+            backward_arrival: angles.forward_arrival,
+            backward_departure: angles.forward_departure,
+        }
+    } else {
+        Angles {
+            forward_arrival: angles.forward_arrival,
+            forward_departure: angles.forward_departure,
+            backward_arrival: angles.backward_arrival,
+            backward_departure: angles.backward_departure,
+        }
+    }
+}
+
+fn merge_chain(id: usize, edges: &Vec<Edge>, chain: &VecDeque<Part>) -> Edge {
+    let mut coords: Vec<Coord> = Vec::new();
+    let mut node_ids: Vec<i64> = Vec::new();
+    for part in chain {
+        let edge = &edges[part.edge_idx];
+        let mut part_coords = edge.linestring.0.clone();
+        let mut part_node_ids = edge.node_ids.clone();
+        if part.reversed {
+            part_coords.reverse();
+            part_node_ids.reverse();
+        }
+        // the first point of each part repeats the last point of the previous one
+        let skip = if coords.is_empty() { 0 } else { 1 };
+        coords.extend(part_coords.into_iter().skip(skip));
+        node_ids.extend(part_node_ids.into_iter().skip(skip));
+    }
+
+    let first = &edges[chain[0].edge_idx];
+    let (forward, backward) = if chain[0].reversed {
+        (first.backward, first.forward)
+    } else {
+        (first.forward, first.backward)
+    };
+    Edge {
+        id,
+        osm_id: first.osm_id,
+        start_node: part_start_node(edges, &chain[0]),
+        end_node: part_end_node(edges, chain.back().unwrap()),
+        node_ids,
+        linestring: LineString::new(coords),
+        forward,
+        backward,
+        follows_terrain: chain
+            .iter()
+            .all(|part| edges[part.edge_idx].follows_terrain),
+        layer: first.layer,
+        roundabout: first.roundabout,
+        incline: if chain.len() == 1 { first.incline } else { None },
+        start_ele: part_start_ele(edges, &chain[0]),
+        end_ele: part_end_ele(edges, chain.back().unwrap()),
+        speed: first.speed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::edge;
+
+    fn angles(first: u16) -> Angles {
+        Angles {
+            forward_arrival: first,
+            forward_departure: first + 1,
+            backward_arrival: first + 2,
+            backward_departure: first + 3,
+        }
+    }
+
+    fn elevation(ascent: f32, descent: f32) -> EdgeElevation {
+        EdgeElevation {
+            ascent,
+            descent,
+            max_gradient: 0.0,
+            start_height: None,
+            end_height: None,
+        }
+    }
+
+    // Simplifies edges 0, 1 and 2 with the given traversal times and angles
+    fn simplify(
+        edges: Vec<Edge>,
+    ) -> (
+        Vec<Edge>,
+        HashMap<usize, (usize, usize)>,
+        HashMap<usize, Angles>,
+        Vec<LinkOrigin>,
+    ) {
+        let traversal_times = HashMap::from([(0, (10, 11)), (1, (20, 21)), (2, (30, 31))]);
+        let angles = HashMap::from([(0, angles(0)), (1, angles(10)), (2, angles(20))]);
+        let elevations = HashMap::from([
+            (0, elevation(1.0, 0.0)),
+            (1, elevation(2.0, 0.0)),
+            (2, elevation(4.0, 0.0)),
+        ]);
+        let (edges, traversal_times, angles, _, origins) =
+            process(edges, traversal_times, angles, elevations);
+        (edges, traversal_times, angles, origins)
+    }
+
+    #[test]
+    fn merges_degree_two_chain() {
+        // edge 1 runs against the direction of the chain
+        let (edges, traversal_times, angles, origins) = simplify(vec![
+            edge(0, 100, vec![1, 2], vec![(0.0, 0.0), (0.001, 0.0)]),
+            edge(1, 101, vec![3, 2], vec![(0.002, 0.0), (0.001, 0.0)]),
+            edge(2, 102, vec![3, 4], vec![(0.002, 0.0), (0.003, 0.0)]),
+        ]);
+        assert_eq!(edges.len(), 1);
+        assert_eq!((edges[0].start_node, edges[0].end_node), (1, 4));
+        assert_eq!(edges[0].node_ids, vec![1, 2, 3, 4]);
+        assert_eq!(traversal_times[&0], (10 + 21 + 30, 11 + 20 + 31));
+        // arriving as along edge 0, departing as along edge 2
+        let angles = &angles[&0];
+        assert_eq!(
+            (
+                angles.forward_arrival,
+                angles.forward_departure,
+                angles.backward_arrival,
+                angles.backward_departure
+            ),
+            (0, 21, 22, 3)
+        );
+        assert_eq!(origins[0].original_edge_ids, vec![0, 1, 2]);
+        assert_eq!(origins[0].osm_way_ids, vec![100, 101, 102]);
+    }
+
+    #[test]
+    fn keeps_roundabouts_apart_from_joining_roads() {
+        let (edges, traversal_times, _, origins) = simplify(vec![
+            edge(0, 100, vec![1, 2], vec![(0.0, 0.0), (0.001, 0.0)]),
+            Edge {
+                roundabout: true,
+                ..edge(1, 101, vec![2, 3], vec![(0.001, 0.0), (0.002, 0.001)])
+            },
+            Edge {
+                roundabout: true,
+                ..edge(2, 101, vec![3, 4], vec![(0.002, 0.001), (0.003, 0.0)])
+            },
+        ]);
+        assert_eq!(edges.len(), 2);
+        assert_eq!(origins[0].original_edge_ids, vec![0]);
+        assert_eq!(origins[1].original_edge_ids, vec![1, 2]);
+        assert_eq!(traversal_times[&0], (10, 11));
+        assert_eq!(traversal_times[&1], (50, 52));
+        assert!(edges[1].roundabout);
+    }
+}
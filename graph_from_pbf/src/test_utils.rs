@@ -0,0 +1,24 @@
+use crate::*;
+
+use geo::{Coord, LineString};
+
+// A two-way edge of way osm_id through the given OSM nodes and coordinates, following the terrain
+pub fn edge(id: usize, osm_id: i64, node_ids: Vec<i64>, coords: Vec<(f64, f64)>) -> Edge {
+    Edge {
+        id,
+        osm_id,
+        start_node: node_ids[0],
+        end_node: *node_ids.last().unwrap(),
+        node_ids,
+        linestring: LineString::new(coords.into_iter().map(|(x, y)| Coord { x, y }).collect()),
+        forward: true,
+        backward: true,
+        follows_terrain: true,
+        layer: 0,
+        roundabout: false,
+        incline: None,
+        start_ele: None,
+        end_ele: None,
+        speed: 1.0,
+    }
+}
@@ -1,92 +1,246 @@
 use crate::*;
-use std::cell::RefCell;
+use crate::elevation_cache::ElevationCache;
+use crate::report::Report;
+use graph_from_pbf::MissingElevation;
 use std::collections::HashMap;
-use std::io::BufReader;
+use std::io::BufWriter;
 
-use elevation::GeoTiffElevation;
 use fs_err::File;
-use geo::{HaversineLength, LineString};
+use geo::{Coord, HaversineLength, LineString};
+use geojson::{Feature, FeatureWriter, Geometry};
 use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
 use rayon::prelude::*;
+use serde::Serialize;
+
+// Heights along an edge in metres, in the direction of the edge or link
+#[derive(Serialize, Clone)]
+pub struct EdgeElevation {
+    pub ascent: f32,
+    pub descent: f32,
+    pub max_gradient: f32,
+    pub start_height: Option<f32>, // sampled from the DEM at the end nodes, None outside it
+    pub end_height: Option<f32>,
+}
+
+impl EdgeElevation {
+    pub fn reversed(&self) -> EdgeElevation {
+        EdgeElevation {
+            ascent: self.descent,
+            descent: self.ascent,
+            max_gradient: self.max_gradient,
+            start_height: self.end_height,
+            end_height: self.start_height,
+        }
+    }
+}
 
 pub fn calculate(
     edges: &Vec<Edge>,
-    tif_path: &str,
+    elevation_cache: &ElevationCache,
     settings: &Settings,
-) -> HashMap<usize, (usize, usize)> {
+    report: &mut Report,
+) -> (
+    HashMap<usize, (usize, usize)>,
+    HashMap<usize, EdgeElevation>,
+    Vec<(usize, usize)>,
+) {
     println!("Calculating traversal times");
     let progress = ProgressBar::new(edges.len() as u64).with_style(ProgressStyle::with_template(
         "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {human_pos}/{human_len} ({per_sec}, {eta})").unwrap());
 
-    let traversal_times: HashMap<usize, (usize, usize)> = edges
+    // For each edge, the number of points without a height and, unless the edge is left out for
+    // being steeper than settings.max_gradient or missing heights, its time and elevation
+    let results: Vec<(usize, usize, Option<((usize, usize), EdgeElevation)>)> = edges
         .into_par_iter()
         .progress_with(progress)
         .map(|edge| {
-            thread_local!(static ELEVATION: RefCell<Option<GeoTiffElevation<BufReader<File>>>> = RefCell::new(None));
-            ELEVATION.with(|elevation_cell| {
-                let mut elevation = elevation_cell.borrow_mut();
-                if elevation.is_none() {
-                    *elevation = Some(GeoTiffElevation::new(BufReader::new(File::open(tif_path).unwrap())));
+            let mut profile = if settings.uses_elevation() {
+                elevation_profile(edge, elevation_cache, settings)
+            } else {
+                flat_profile(edge)
+            };
+            let missing_points = profile.iter().filter(|(_, height)| height.is_none()).count();
+            if missing_points > 0 {
+                match settings.missing_elevation {
+                    // steps without heights are treated as flat when calculating times
+                    MissingElevation::AssumeFlat => {}
+                    MissingElevation::DropEdge => return (edge.id, missing_points, None),
+                    MissingElevation::Interpolate => interpolate_missing_heights(&mut profile),
                 }
+            }
+            if let Some(threshold) = settings.elevation_hysteresis {
+                smooth_profile(&mut profile, threshold);
+            }
+            if let Some(max_gradient) = settings.max_gradient {
+                if edge.incline.map_or(false, |incline| incline.abs() > max_gradient)
+                    || steepest_gradient(&profile) > max_gradient
+                {
+                    return (edge.id, missing_points, None);
+                }
+            }
 
-                let traversal_time = calculate_edge_traversal_time(
-                    &edge.linestring,
-                    elevation.as_mut().unwrap(),
-                    settings.speed,
-                    settings.ascention_speed
-                );
-                (edge.id, traversal_time)
-            })
+            let traversal_time = calculate_edge_traversal_time(
+                edge,
+                &profile,
+                settings.ascention_speed,
+                settings.assisted_gradient.unwrap_or(0.0),
+            );
+            let elevation = edge_elevation(
+                &profile,
+                elevation_cache.node_height(edge.start_node, start_coord(edge), settings.dem_interpolation),
+                elevation_cache.node_height(edge.end_node, end_coord(edge), settings.dem_interpolation),
+            );
+            (edge.id, missing_points, Some((traversal_time, elevation)))
         })
         .collect();
-    traversal_times
+
+    let mut traversal_times: HashMap<usize, (usize, usize)> = HashMap::new();
+    let mut elevations: HashMap<usize, EdgeElevation> = HashMap::new();
+    let mut missing_elevation_edges: Vec<(usize, usize)> = Vec::new();
+    for (edge_id, missing_points, result) in results {
+        if missing_points > 0 {
+            missing_elevation_edges.push((edge_id, missing_points));
+            report.missing_elevation.point_count += missing_points;
+            if result.is_none() && matches!(settings.missing_elevation, MissingElevation::DropEdge) {
+                report.missing_elevation.dropped_edge_count += 1;
+            }
+        }
+        if let Some((traversal_time, elevation)) = result {
+            traversal_times.insert(edge_id, traversal_time);
+            elevations.insert(edge_id, elevation);
+        }
+    }
+    report.missing_elevation.edge_count = missing_elevation_edges.len();
+    if !missing_elevation_edges.is_empty() {
+        println!(
+            "{} points on {} edges are outside the DEM",
+            report.missing_elevation.point_count, report.missing_elevation.edge_count
+        );
+    }
+    (traversal_times, elevations, missing_elevation_edges)
+}
+
+// Writes the edges with points outside the DEM and how many points were missing
+pub fn write_missing_elevation(
+    edges: &Vec<Edge>,
+    missing_elevation_edges: &Vec<(usize, usize)>,
+    output_directory: &str,
+    mode: &str,
+) -> Result<()> {
+    let missing_points: HashMap<usize, usize> = missing_elevation_edges.iter().copied().collect();
+    let path = format!("{output_directory}/{mode}_missing_elevation.geojson");
+    let mut out = FeatureWriter::from_writer(BufWriter::new(File::create(path)?));
+    for edge in edges {
+        if let Some(count) = missing_points.get(&edge.id) {
+            let mut f = Feature::from(Geometry::from(&edge.linestring));
+            f.set_property("osm_way_id", edge.osm_id);
+            f.set_property("missing_points", *count);
+            out.write_feature(&f)?;
+        }
+    }
+    Ok(())
+}
+
+// Orients the elevation of each link's edge in the direction of travel, indexed by link_id
+pub fn link_elevations(
+    elevations: &HashMap<usize, EdgeElevation>,
+    link_edges: &Vec<(usize, bool)>,
+) -> Vec<EdgeElevation> {
+    link_edges
+        .iter()
+        .map(|(edge_id, forward)| {
+            if *forward {
+                elevations[edge_id].clone()
+            } else {
+                elevations[edge_id].reversed()
+            }
+        })
+        .collect()
+}
+
+fn start_coord(edge: &Edge) -> Coord {
+    edge.linestring.0[0]
+}
+
+fn end_coord(edge: &Edge) -> Coord {
+    *edge.linestring.0.last().unwrap()
+}
+
+// Height of each graph node, indexed by node id, as sampled from the DEM at its OSM node. Heights
+// derived along an edge, e.g. from an incline tag, are left out so each node has one true height.
+pub fn node_elevations(
+    elevation_cache: &ElevationCache,
+    settings: &Settings,
+    graph_nodes_lookup: &HashMap<i64, (usize, Coord)>,
+) -> Vec<Option<f32>> {
+    let mut node_elevations = vec![None; graph_nodes_lookup.len()];
+    for (osm_id, (id, coord)) in graph_nodes_lookup {
+        node_elevations[*id] =
+            elevation_cache.node_height(*osm_id, *coord, settings.dem_interpolation);
+    }
+    node_elevations
+}
+
+// The end heights are the DEM heights at the end nodes rather than those of the profile, which may
+// be made up, e.g. starting from 0 on an incline-tagged bridge outside the DEM, or smoothed
+fn edge_elevation(
+    profile: &Vec<(f32, Option<f32>)>,
+    start_height: Option<f32>,
+    end_height: Option<f32>,
+) -> EdgeElevation {
+    let mut ascent: f32 = 0.0;
+    let mut descent: f32 = 0.0;
+    for step in profile.windows(2) {
+        if let (Some(height1), Some(height2)) = (step[0].1, step[1].1) {
+            if height2 > height1 {
+                ascent += height2 - height1;
+            } else {
+                descent += height1 - height2;
+            }
+        }
+    }
+    EdgeElevation {
+        ascent,
+        descent,
+        max_gradient: steepest_gradient(profile),
+        start_height,
+        end_height,
+    }
 }
 
 fn calculate_edge_traversal_time(
-    linestring: &LineString,
-    elevation: &mut GeoTiffElevation<BufReader<File>>,
-    speed: f32,
+    edge: &Edge,
+    profile: &Vec<(f32, Option<f32>)>,
     ascention_speed: f32, // 6 s/m for walking to follow Naismith's rule
+    assisted_gradient: f32,
 ) -> (usize, usize) {
+    let speed = edge.speed;
     let mut forward_traversal_time: f32 = 0.0;
     let mut backward_traversal_time: f32 = 0.0;
 
-    for line in linestring.lines() {
-        let pt1 = line.start;
-
-        let length = line.haversine_length() as f32;
+    for step in profile.windows(2) {
+        let (distance1, height1) = step[0];
+        let (distance2, height2) = step[1];
+        let length = distance2 - distance1;
 
-        let height1 = match elevation.get_height_for_lon_lat(pt1.x as f32, pt1.y as f32) {
-            Some(height) => height,
-            None => {
-                // if coordinates outside the UK elevation model, assume flat terrain
-                // TODO: remove these coordinates from the graph
-                println!("Failed to get height for lon: {}, lat: {}", pt1.x, pt1.y);
-                forward_traversal_time += length / speed;
-                backward_traversal_time += length / speed;
-                continue;
-            }
-        };
-        let pt2 = line.end;
-        let height2 = match elevation.get_height_for_lon_lat(pt2.x as f32, pt2.y as f32) {
-            Some(height) => height,
-            None => {
-                println!("Failed to get height for lon: {}, lat: {}", pt2.x, pt2.y);
-                forward_traversal_time += length / speed;
-                backward_traversal_time += length / speed;
-                continue;
-            }
+        // if coordinates outside the elevation model, assume flat terrain
+        let (Some(height1), Some(height2)) = (height1, height2) else {
+            forward_traversal_time += length / speed;
+            backward_traversal_time += length / speed;
+            continue;
         };
+        // only the height gained beyond the assisted gradient slows the climb
         let height_diff = height2 - height1;
+        let assisted_height = assisted_gradient * length;
         forward_traversal_time += length / speed
-            + if height_diff > 0.0 {
-                height_diff * ascention_speed
+            + if height_diff > assisted_height {
+                (height_diff - assisted_height) * ascention_speed
             } else {
                 0.0
             };
         backward_traversal_time += length / speed
-            + if height_diff < 0.0 {
-                -height_diff * ascention_speed
+            + if -height_diff > assisted_height {
+                (-height_diff - assisted_height) * ascention_speed
             } else {
                 0.0
             };
@@ -103,3 +257,348 @@ fn calculate_edge_traversal_time(
     };
     (forward, backward)
 }
+
+// Distance along the edge and height of each sample point, from the heights cached per node and
+// segment. Without a sample spacing the heights are taken at the vertices only, so long straight
+// segments over a hill register no climb.
+// Bridges and tunnels don't follow the terrain beneath them, so their heights come from the
+// incline tag or are interpolated between the ends of the edge. At each end, a height from the
+// node's ele tag is preferred to the DEM, which gives the ground or water level below a bridge.
+fn elevation_profile(
+    edge: &Edge,
+    elevation_cache: &ElevationCache,
+    settings: &Settings,
+) -> Vec<(f32, Option<f32>)> {
+    let interpolation = settings.dem_interpolation;
+    if edge.follows_terrain {
+        let mut profile = vec![(
+            0.0,
+            elevation_cache.node_height(edge.start_node, start_coord(edge), interpolation),
+        )];
+        let mut total: f32 = 0.0;
+        for (line, nodes) in edge.linestring.lines().zip(edge.node_ids.windows(2)) {
+            let length = line.haversine_length() as f32;
+            let interior_heights = elevation_cache.segment_heights(
+                nodes[0],
+                nodes[1],
+                line,
+                settings.elevation_sample_spacing,
+                interpolation,
+            );
+            let num_steps = (interior_heights.len() + 1) as f32;
+            for (step, height) in interior_heights.into_iter().enumerate() {
+                profile.push((total + length * (step + 1) as f32 / num_steps, height));
+            }
+            total += length;
+            profile.push((total, elevation_cache.node_height(nodes[1], line.end, interpolation)));
+        }
+        return profile;
+    }
+
+    let distances = cumulative_distances(&edge.linestring);
+    let start_height = edge
+        .start_ele
+        .or_else(|| elevation_cache.node_height(edge.start_node, start_coord(edge), interpolation));
+    if let Some(incline) = edge.incline {
+        // only relative heights matter for traversal times, so start from 0 outside the DEM. These
+        // heights are never reported as the heights of the edge's nodes.
+        let start_height = start_height.unwrap_or(0.0);
+        return distances
+            .into_iter()
+            .map(|distance| (distance, Some(start_height + distance * incline)))
+            .collect();
+    }
+    let (Some(start_height), Some(end_height)) = (
+        start_height,
+        edge.end_ele
+            .or_else(|| elevation_cache.node_height(edge.end_node, end_coord(edge), interpolation)),
+    ) else {
+        return distances.into_iter().map(|distance| (distance, None)).collect();
+    };
+    let total_distance = *distances.last().unwrap();
+    distances
+        .into_iter()
+        .map(|distance| {
+            if total_distance == 0.0 {
+                (distance, Some(start_height))
+            } else {
+                let height = start_height + (end_height - start_height) * distance / total_distance;
+                (distance, Some(height))
+            }
+        })
+        .collect()
+}
+
+// Fills heights outside the DEM by interpolating between the nearest known heights along the edge.
+// Points before the first or after the last known height take that height.
+fn interpolate_missing_heights(profile: &mut Vec<(f32, Option<f32>)>) {
+    let known: Vec<(f32, f32)> = profile
+        .iter()
+        .filter_map(|(distance, height)| height.map(|height| (*distance, height)))
+        .collect();
+    if known.is_empty() {
+        return;
+    }
+    for (distance, height) in profile.iter_mut() {
+        if height.is_some() {
+            continue;
+        }
+        let next = known.partition_point(|(known_distance, _)| known_distance < distance);
+        *height = match (next.checked_sub(1).map(|prev| known[prev]), known.get(next)) {
+            (Some((distance1, height1)), Some((distance2, height2))) => {
+                if distance2 > &distance1 {
+                    Some(height1 + (height2 - height1) * (*distance - distance1) / (distance2 - distance1))
+                } else {
+                    Some(height1)
+                }
+            }
+            (Some((_, height)), None) => Some(height),
+            (None, Some((_, height))) => Some(*height),
+            (None, None) => None,
+        };
+    }
+}
+
+// Flattens oscillations smaller than the threshold, e.g. from DEM noise along a flat street. Only
+// the ends of the edge and the peaks and troughs between them differing from their neighbours by
+// at least the threshold are kept, with heights interpolated linearly in between, so real slopes
+// keep their gradient.
+fn smooth_profile(profile: &mut Vec<(f32, Option<f32>)>, threshold: f32) {
+    let known: Vec<(f32, f32)> = profile
+        .iter()
+        .filter_map(|(distance, height)| height.map(|height| (*distance, height)))
+        .collect();
+    if known.len() < 2 {
+        return;
+    }
+
+    let mut anchors: Vec<(f32, f32)> = vec![known[0]];
+    // the most extreme point since the last anchor, and whether heights are rising towards it
+    let mut extreme: Option<((f32, f32), bool)> = None;
+    for &(distance, height) in &known[1..] {
+        let last_anchor_height = anchors.last().unwrap().1;
+        match extreme {
+            None => {
+                if (height - last_anchor_height).abs() >= threshold {
+                    extreme = Some(((distance, height), height > last_anchor_height));
+                }
+            }
+            Some(((_, extreme_height), rising)) => {
+                if (rising && height >= extreme_height) || (!rising && height <= extreme_height) {
+                    extreme = Some(((distance, height), rising));
+                } else if (height - extreme_height).abs() >= threshold {
+                    anchors.push(extreme.unwrap().0);
+                    extreme = Some(((distance, height), !rising));
+                }
+            }
+        }
+    }
+    // Both ends keep their true heights, so edges sharing a node agree on its height and a climb
+    // split into edges shorter than the threshold isn't flattened away. Only the points in
+    // between are smoothed.
+    let end = *known.last().unwrap();
+    if let Some((point, _)) = extreme {
+        if point.0 < end.0 {
+            anchors.push(point);
+        }
+    }
+    if anchors.last().unwrap().0 < end.0 {
+        anchors.push(end);
+    }
+    if anchors.len() < 2 {
+        return;
+    }
+
+    let mut segment = 0;
+    for (distance, height) in profile.iter_mut() {
+        if height.is_none() {
+            continue;
+        }
+        while segment + 2 < anchors.len() && *distance > anchors[segment + 1].0 {
+            segment += 1;
+        }
+        let (start_distance, start_height) = anchors[segment];
+        let (end_distance, end_height) = anchors[segment + 1];
+        *height = Some(if end_distance > start_distance {
+            let fraction = (*distance - start_distance) / (end_distance - start_distance);
+            start_height + (end_height - start_height) * fraction.clamp(0.0, 1.0)
+        } else {
+            start_height
+        });
+    }
+}
+
+// Gradients are measured over stretches of at least GRADIENT_WINDOW metres, so that short segments
+// between closely spaced vertices don't turn small height errors into extreme gradients. A stretch
+// left over at the end of an edge is merged into the one before it, and an edge shorter than the
+// window is measured as if it were GRADIENT_WINDOW long.
+const GRADIENT_WINDOW: f32 = 20.0;
+
+fn steepest_gradient(profile: &Vec<(f32, Option<f32>)>) -> f32 {
+    let last = profile.len() - 1;
+    let mut window_ends = Vec::new();
+    let mut start = 0;
+    for end in 1..=last {
+        if profile[end].0 - profile[start].0 >= GRADIENT_WINDOW {
+            window_ends.push(end);
+            start = end;
+        }
+    }
+    match window_ends.last_mut() {
+        Some(end) => *end = last,
+        None => window_ends.push(last),
+    }
+
+    let mut steepest: f32 = 0.0;
+    let mut start = 0;
+    for end in window_ends {
+        if let (Some(start_height), Some(end_height)) = (profile[start].1, profile[end].1) {
+            let length = (profile[end].0 - profile[start].0).max(GRADIENT_WINDOW);
+            steepest = steepest.max((end_height - start_height).abs() / length);
+        }
+        start = end;
+    }
+    steepest
+}
+
+// The profile of a mode that doesn't use heights, without sampling the DEM
+fn flat_profile(edge: &Edge) -> Vec<(f32, Option<f32>)> {
+    cumulative_distances(&edge.linestring)
+        .into_iter()
+        .map(|distance| (distance, Some(0.0)))
+        .collect()
+}
+
+fn cumulative_distances(linestring: &LineString) -> Vec<f32> {
+    let mut distances = vec![0.0];
+    let mut total: f32 = 0.0;
+    for line in linestring.lines() {
+        total += line.haversine_length() as f32;
+        distances.push(total);
+    }
+    distances
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::edge;
+
+    fn profile(heights: &[f32]) -> Vec<(f32, Option<f32>)> {
+        heights
+            .iter()
+            .enumerate()
+            .map(|(idx, height)| (idx as f32 * 10.0, Some(*height)))
+            .collect()
+    }
+
+    fn heights(profile: &Vec<(f32, Option<f32>)>) -> Vec<f32> {
+        profile.iter().map(|(_, height)| height.unwrap()).collect()
+    }
+
+    #[test]
+    fn end_heights_match_node_heights() {
+        // an incline-tagged bridge climbs well past the DEM height at its end, and a noisy edge is
+        // smoothed, but both report the DEM heights of their nodes
+        let edges = vec![
+            Edge {
+                follows_terrain: false,
+                incline: Some(0.1),
+                ..edge(0, 100, vec![1, 2], vec![(0.0, 0.0), (0.001, 0.0)])
+            },
+            edge(1, 101, vec![3, 4, 2], vec![(0.001, 0.001), (0.001, 0.0005), (0.001, 0.0)]),
+        ];
+        let coords = [(1, (0.0, 0.0)), (2, (0.001, 0.0)), (3, (0.001, 0.001)), (4, (0.001, 0.0005))]
+            .map(|(node, (x, y))| (node, Coord { x, y }));
+        let elevation_cache = ElevationCache::from_node_heights(
+            coords
+                .into_iter()
+                .zip([10.0, 12.3, 11.0, 11.4])
+                .map(|((node, coord), height)| (node, coord, Some(height)))
+                .collect(),
+        );
+        let settings: Settings = serde_json::from_str(
+            r#"{"mode": "walk", "tag_pairs": [], "speed": 1.33, "ascention_speed": 6.0,
+                "descent_speed": 0.0, "elevation_hysteresis": 5.0}"#,
+        )
+        .unwrap();
+        let (_, elevations, _) =
+            calculate(&edges, &elevation_cache, &settings, &mut Report::default());
+        let graph_nodes_lookup: HashMap<i64, (usize, Coord)> = coords[..3]
+            .iter()
+            .enumerate()
+            .map(|(id, (node, coord))| (*node, (id, *coord)))
+            .collect();
+        let node_heights = node_elevations(&elevation_cache, &settings, &graph_nodes_lookup);
+        assert_eq!(node_heights, vec![Some(10.0), Some(12.3), Some(11.0)]);
+
+        let link_edges = vec![(0, true), (0, false), (1, true), (1, false)];
+        let links = link_elevations(&elevations, &link_edges);
+        for ((edge_id, forward), elevation) in link_edges.iter().zip(&links) {
+            let edge = &edges[*edge_id];
+            let (from, to) = if *forward {
+                (edge.start_node, edge.end_node)
+            } else {
+                (edge.end_node, edge.start_node)
+            };
+            assert_eq!(elevation.start_height, node_heights[graph_nodes_lookup[&from].0]);
+            assert_eq!(elevation.end_height, node_heights[graph_nodes_lookup[&to].0]);
+        }
+        // the profile of the bridge still follows its incline
+        assert!(links[0].ascent > 10.0);
+    }
+
+    #[test]
+    fn smoothing_keeps_the_end_heights() {
+        // the rise between the ends is below the threshold, but the ends are still kept
+        let mut smoothed = profile(&[10.0, 10.4, 9.9, 10.6]);
+        smooth_profile(&mut smoothed, 1.0);
+        let smoothed = heights(&smoothed);
+        assert_eq!(smoothed[0], 10.0);
+        assert_eq!(smoothed[3], 10.6);
+        assert!((smoothed[1] - 10.2).abs() < 1e-5);
+        assert!((smoothed[2] - 10.4).abs() < 1e-5);
+    }
+
+    #[test]
+    fn smoothing_flattens_noise() {
+        let mut smoothed = profile(&[10.0, 10.4, 9.7, 10.3, 9.8, 10.0]);
+        smooth_profile(&mut smoothed, 1.0);
+        assert_eq!(heights(&smoothed), vec![10.0; 6]);
+    }
+
+    #[test]
+    fn smoothing_keeps_real_climbs() {
+        // over a hill, with noise on the way up
+        let original = profile(&[10.0, 15.0, 14.8, 20.0, 15.0, 10.0]);
+        let mut smoothed = original.clone();
+        smooth_profile(&mut smoothed, 1.0);
+        let smoothed = heights(&smoothed);
+        assert_eq!(smoothed[0], 10.0);
+        assert_eq!(smoothed[3], 20.0);
+        assert_eq!(smoothed[5], 10.0);
+        // the noise is flattened into a steady climb to the top
+        assert!(smoothed[1] < smoothed[2] && smoothed[2] < smoothed[3]);
+        let elevation = edge_elevation(&original, Some(10.0), Some(10.0));
+        let smoothed_elevation = edge_elevation(&profile(&smoothed), Some(10.0), Some(10.0));
+        assert!((smoothed_elevation.ascent - 10.0).abs() < 1e-4);
+        assert!((smoothed_elevation.descent - 10.0).abs() < 1e-4);
+        assert!(elevation.ascent > smoothed_elevation.ascent);
+    }
+
+    #[test]
+    fn short_edges_are_measured_over_the_window() {
+        // a 1 m step in the DEM over a 10 m edge
+        let profile = vec![(0.0, Some(10.0)), (10.0, Some(11.0))];
+        assert_eq!(steepest_gradient(&profile), 0.05);
+    }
+
+    #[test]
+    fn short_tails_are_merged_into_the_last_window() {
+        let profile = vec![(0.0, Some(0.0)), (20.0, Some(0.0)), (40.0, Some(0.0)), (45.0, Some(1.0))];
+        assert_eq!(steepest_gradient(&profile), 0.04);
+        // a climb over a whole window still counts
+        let profile = vec![(0.0, Some(0.0)), (20.0, Some(4.0)), (40.0, Some(4.0)), (45.0, Some(4.0))];
+        assert_eq!(steepest_gradient(&profile), 0.2);
+    }
+}
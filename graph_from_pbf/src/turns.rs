@@ -0,0 +1,137 @@
+use graph_from_pbf::TurnCosts;
+
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Turn {
+    Straight,
+    Left,
+    Right,
+    UTurn,
+}
+
+// For each link, indexed by link_id, the links leaving its end node with the kind and cost of the
+// turn onto them. Link ids follow the order of the links in the graph, so iterating the graph in
+// order visits the incoming links by link_id.
+pub fn calculate(
+    graph: &Vec<Vec<(usize, usize, u16, u16, u32)>>,
+    link_edges: &Vec<(usize, bool)>,
+    turn_costs: &TurnCosts,
+) -> Vec<Vec<(u32, Turn, usize)>> {
+    println!("Calculating turn costs");
+    let mut costs: Vec<Vec<(u32, Turn, usize)>> = Vec::with_capacity(link_edges.len());
+    for links in graph {
+        for &(_, end_node, departure_angle, _, link_id) in links {
+            let (edge_id, forward) = link_edges[link_id as usize];
+            let Some(next_links) = graph.get(end_node) else {
+                costs.push(Vec::new());
+                continue;
+            };
+            costs.push(
+                next_links
+                    .iter()
+                    .map(|&(_, _, _, arrival_angle, next_link_id)| {
+                        let reverses_edge =
+                            link_edges[next_link_id as usize] == (edge_id, !forward);
+                        let turn = if reverses_edge {
+                            Turn::UTurn
+                        } else {
+                            classify(turn_angle(departure_angle, arrival_angle), turn_costs)
+                        };
+                        (next_link_id, turn, cost(turn, turn_costs))
+                    })
+                    .collect(),
+            );
+        }
+    }
+    costs
+}
+
+// Change in bearing from the end of one link to the start of the next, in degrees from -180 to
+// 180, positive turning clockwise (right)
+fn turn_angle(departure_angle: u16, arrival_angle: u16) -> i32 {
+    let angle = (arrival_angle as i32 - departure_angle as i32).rem_euclid(360);
+    if angle > 180 {
+        angle - 360
+    } else {
+        angle
+    }
+}
+
+fn classify(turn_angle: i32, turn_costs: &TurnCosts) -> Turn {
+    if turn_angle.abs() <= turn_costs.straight_angle as i32 {
+        Turn::Straight
+    } else if turn_angle.abs() > turn_costs.u_turn_angle as i32 {
+        Turn::UTurn
+    } else if turn_angle > 0 {
+        Turn::Right
+    } else {
+        Turn::Left
+    }
+}
+
+fn cost(turn: Turn, turn_costs: &TurnCosts) -> usize {
+    match turn {
+        Turn::Straight => turn_costs.straight,
+        Turn::Left => turn_costs.left,
+        Turn::Right => turn_costs.right,
+        Turn::UTurn => turn_costs.u_turn,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn turn_costs() -> TurnCosts {
+        serde_json::from_str(r#"{"left": 5, "right": 20, "u_turn": 60}"#).unwrap()
+    }
+
+    #[test]
+    fn turns_across_north() {
+        assert_eq!(turn_angle(350, 10), 20);
+        assert_eq!(turn_angle(10, 350), -20);
+        assert_eq!(turn_angle(300, 30), 90);
+        assert_eq!(turn_angle(30, 300), -90);
+    }
+
+    #[test]
+    fn reversals() {
+        assert_eq!(turn_angle(0, 180), 180);
+        assert_eq!(turn_angle(90, 270), 180);
+        assert_eq!(turn_angle(270, 90), 180);
+        assert_eq!(classify(180, &turn_costs()), Turn::UTurn);
+    }
+
+    #[test]
+    fn thresholds() {
+        let turn_costs = turn_costs();
+        assert_eq!(classify(30, &turn_costs), Turn::Straight);
+        assert_eq!(classify(-30, &turn_costs), Turn::Straight);
+        assert_eq!(classify(31, &turn_costs), Turn::Right);
+        assert_eq!(classify(-31, &turn_costs), Turn::Left);
+        assert_eq!(classify(150, &turn_costs), Turn::Right);
+        assert_eq!(classify(-150, &turn_costs), Turn::Left);
+        assert_eq!(classify(151, &turn_costs), Turn::UTurn);
+        assert_eq!(classify(-151, &turn_costs), Turn::UTurn);
+    }
+
+    #[test]
+    fn turns_from_the_graph() {
+        // link 0 heads east along edge 0 to node 1, where links 1 and 2 head north and south and
+        // link 3 goes back along edge 0. Edge 0 curves, so link 3 starts heading east.
+        let graph: Vec<Vec<(usize, usize, u16, u16, u32)>> = vec![
+            vec![(10, 1, 90, 90, 0)],
+            vec![(10, 2, 0, 0, 1), (10, 3, 180, 180, 2), (10, 0, 270, 80, 3)],
+            vec![],
+            vec![],
+        ];
+        let link_edges = vec![(0, true), (1, true), (2, true), (0, false)];
+        let costs = calculate(&graph, &link_edges, &turn_costs());
+        assert_eq!(costs[0], vec![(1, Turn::Left, 5), (2, Turn::Right, 20), (3, Turn::UTurn, 60)]);
+        // from link 3 back at node 0, only link 0 leaves, along the same edge
+        assert_eq!(costs[3], vec![(0, Turn::UTurn, 60)]);
+        assert!(costs[1].is_empty() && costs[2].is_empty());
+    }
+}